@@ -0,0 +1,1308 @@
+//! The versioned REST interface under `/api/v1`. Requests authenticate with the session id from
+//! `POST /api/v1/sessions` or an API token from `POST /api/v1/users/me/tokens` as a bearer token.
+//! API tokens need the `read` scope for `GET` requests, `upload` to upload images and `admin` for
+//! every other change. Successful responses are the JSON resource itself, collections are
+//! `{"items": [...], "next": cursor, "prev": cursor}` and errors are `{"error": message}` with a
+//! matching status code. The OpenAPI document generated from the request and response types is
+//! served at `/api/v1/openapi.json`.
+
+use crate::api_tokens::{self, ApiToken, CreatedToken};
+use crate::gallery_operations::find_viewable_gallery_by_id;
+use crate::image_operations::{ImageFilter, OwnedImage};
+use crate::union_structs::{
+    self, GalleryCreate, GalleryPatch, ImageCreate, ImageListing, ImagePatch, ImageStatus, Login,
+    Scope, SettingsUpdate, Signup, TokenCreate,
+};
+use crate::{
+    gallery_image_rows, gallery_operations, image_operations, labels, mysql_init, pagination,
+    store_image, user_gallery_rows, PageQuery,
+};
+use actix_web::body::BoxBody;
+use actix_web::dev::Payload;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Responder, ResponseError, Route};
+use futures_util::stream::StreamExt as _;
+use lazy_static::lazy_static;
+use mysql::params;
+use mysql::prelude::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use utoipa::openapi::path::{Operation, PathItem};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+/// Routes every operation in the OpenAPI document to the handler it was generated from, so the
+/// paths and methods are only written in the handlers' `utoipa::path` attributes. Paths are sorted,
+/// so a literal segment such as `/users/me` is tried before a parameter such as `/users/{username}`.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    let mut scope = web::scope("/api/v1");
+    for (path, item) in ApiDoc::openapi().paths.paths {
+        for (method, operation) in operations(&item) {
+            let route = operation
+                .operation_id
+                .as_deref()
+                .and_then(handler_route)
+                .expect("Documented operation has no handler");
+            scope = scope.route(&path, route.method(method));
+        }
+    }
+    cfg.service(
+        scope.default_service(web::to(|| async { error(StatusCode::NOT_FOUND, "Unknown endpoint").error_response() })),
+    );
+}
+
+/// The operations documented for a path, with their methods.
+fn operations(item: &PathItem) -> Vec<(Method, &Operation)> {
+    vec![
+        (Method::GET, &item.get),
+        (Method::POST, &item.post),
+        (Method::PUT, &item.put),
+        (Method::PATCH, &item.patch),
+        (Method::DELETE, &item.delete),
+    ]
+    .into_iter()
+    .filter_map(|(method, operation)| operation.as_ref().map(|operation| (method, operation)))
+    .collect()
+}
+
+/// Lists every endpoint once, for both the OpenAPI document and the routes to their handlers.
+macro_rules! endpoints {
+    ($($handler:ident),* $(,)?) => {
+        /// The OpenAPI document for everything under `/api/v1`, which is also its server url.
+        #[derive(OpenApi)]
+        #[openapi(
+            info(title = "Union", description = "Image galleries"),
+            servers((url = "/api/v1")),
+            paths($($handler),*),
+            modifiers(&BearerAuth)
+        )]
+        struct ApiDoc;
+
+        /// A route to the handler whose operation id, its function name, is given.
+        fn handler_route(operation_id: &str) -> Option<Route> {
+            match operation_id {
+                $(stringify!($handler) => Some(web::route().to($handler)),)*
+                _ => None,
+            }
+        }
+
+        /// The request and response body schemas of the handler whose operation id is given, as
+        /// its signature names them.
+        #[cfg(test)]
+        fn handler_bodies(operation_id: &str) -> Option<(Option<Value>, Option<Value>)> {
+            match operation_id {
+                $(stringify!($handler) => Some(tests::bodies($handler)),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+endpoints!(
+    openapi_document,
+    create_session,
+    end_session,
+    create_user,
+    get_me,
+    update_me,
+    list_tokens,
+    create_token,
+    revoke_token,
+    get_user,
+    list_user_galleries,
+    create_gallery,
+    get_gallery,
+    update_gallery,
+    delete_gallery,
+    list_child_galleries,
+    list_gallery_images,
+    upload_image,
+    get_image,
+    update_image,
+    delete_image,
+    list_image_labels,
+    add_image_label,
+    remove_image_label,
+    list_labels,
+);
+
+struct BearerAuth;
+
+impl Modify for BearerAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .add_security_scheme("bearer", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+lazy_static! {
+    static ref OPENAPI_DOCUMENT: String = ApiDoc::openapi()
+        .to_pretty_json()
+        .expect("Failed to serialize OpenAPI document");
+}
+
+#[utoipa::path(
+    get,
+    path = "/openapi.json",
+    responses((status = 200, description = "This document", content_type = "application/json")),
+)]
+async fn openapi_document() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .body(OPENAPI_DOCUMENT.as_str())
+}
+
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(ErrorBody {
+            error: self.message.clone(),
+        })
+    }
+}
+
+type ApiResult = Result<HttpResponse, ApiError>;
+
+/// A successful JSON response. Its type names the body, so that the tests can compare the body
+/// each handler returns with the one documented for it.
+struct Reply<T> {
+    status: StatusCode,
+    body: T,
+}
+
+impl<T: Serialize> Responder for Reply<T> {
+    type Body = BoxBody;
+    fn respond_to(self, _: &HttpRequest) -> HttpResponse {
+        HttpResponse::build(self.status).json(self.body)
+    }
+}
+
+type ApiReply<T> = Result<Reply<T>, ApiError>;
+
+fn reply<T>(status: StatusCode, body: T) -> ApiReply<T> {
+    Ok(Reply { status, body })
+}
+
+fn error(status: StatusCode, message: &str) -> ApiError {
+    ApiError {
+        status,
+        message: String::from(message),
+    }
+}
+
+fn not_found() -> ApiError {
+    error(StatusCode::NOT_FOUND, "Not found")
+}
+
+/// The user making the request, if it carries a valid bearer token that can read.
+fn viewer(hr: &HttpRequest) -> Option<mysql::Row> {
+    api_tokens::authorize(hr, Scope::Read)
+}
+
+/// The user making the request, whose bearer token must have the scope.
+fn authorized(hr: &HttpRequest, scope: Scope) -> Result<mysql::Row, ApiError> {
+    match api_tokens::bearer_user(hr) {
+        Some((user_row, scopes)) if api_tokens::grants(&scopes, scope) => Ok(user_row),
+        Some(_) => Err(error(StatusCode::FORBIDDEN, &format!("The token needs the {} scope", scope.name()))),
+        None => Err(error(StatusCode::UNAUTHORIZED, "Missing or invalid bearer token")),
+    }
+}
+
+/// Like `authorized`, with the scope the request's method needs.
+fn authenticated(hr: &HttpRequest) -> Result<mysql::Row, ApiError> {
+    let scope = if hr.method() == Method::GET { Scope::Read } else { Scope::Admin };
+    authorized(hr, scope)
+}
+
+/// The most a request body other than an uploaded image may hold, as for actix's own extractors.
+const MAX_BODY_BYTES: usize = 256 * 1024;
+
+/// A JSON request body holding one of the input structs. It is only read when the handler parses
+/// it, after authenticating the request, and its type lets the tests compare it with the
+/// documented request body.
+struct Body<T> {
+    payload: Payload,
+    body: PhantomData<T>,
+}
+
+impl<T> FromRequest for Body<T> {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+    fn from_request(_: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(Ok(Body {
+            payload: payload.take(),
+            body: PhantomData,
+        }))
+    }
+}
+
+impl<T: DeserializeOwned> Body<T> {
+    /// Reads the body, which may be at most `MAX_BODY_BYTES`, and parses it as `parse_body` does.
+    async fn parse(self, extra: &[(&str, Value)]) -> Result<T, ApiError> {
+        self.parse_within(MAX_BODY_BYTES, extra).await
+    }
+    async fn parse_within(mut self, limit: usize, extra: &[(&str, Value)]) -> Result<T, ApiError> {
+        let mut bytes = web::BytesMut::new();
+        while let Some(item) = self.payload.next().await {
+            let item = item.map_err(|_| error(StatusCode::BAD_REQUEST, "Failed to read request body"))?;
+            if bytes.len() + item.len() > limit {
+                return Err(error(StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large"));
+            }
+            bytes.extend_from_slice(&item);
+        }
+        parse_body(&bytes, extra)
+    }
+}
+
+/// Parses a JSON request body into one of the input structs. `extra` fills in fields the
+/// struct shares with the websocket interface but that the REST interface takes from elsewhere,
+/// such as the session id.
+fn parse_body<T: DeserializeOwned>(body: &[u8], extra: &[(&str, Value)]) -> Result<T, ApiError> {
+    let malformed = || error(StatusCode::BAD_REQUEST, "Malformed request body");
+    let mut value: Value = serde_json::from_slice(body).map_err(|_| malformed())?;
+    let object = value.as_object_mut().ok_or_else(malformed)?;
+    for (name, extra_value) in extra {
+        object.insert(String::from(*name), extra_value.clone());
+    }
+    serde_json::from_value(value).map_err(|_| malformed())
+}
+
+/// A page of a listing, with the cursors of the pages next to it.
+#[derive(Serialize, ToSchema)]
+struct Collection<T> {
+    items: Vec<T>,
+    next: Option<i32>,
+    prev: Option<i32>,
+}
+
+fn collection<T>(items: Vec<T>, cursors: pagination::Cursors) -> ApiReply<Collection<T>> {
+    reply(
+        StatusCode::OK,
+        Collection {
+            items,
+            next: cursors.next,
+            prev: cursors.prev,
+        },
+    )
+}
+
+#[derive(Serialize, ToSchema)]
+struct ErrorBody {
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionResource {
+    token: String,
+    token_type: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UserResource {
+    id: i32,
+    username: String,
+    url: String,
+}
+
+/// The signed in user with their settings.
+#[derive(Serialize, ToSchema)]
+struct SettingsResource {
+    #[serde(flatten)]
+    user: UserResource,
+    email: String,
+    strip_metadata: String,
+    keep_original_metadata: bool,
+    gallery_order: String,
+    accessibility_strict: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+struct GalleryResource {
+    id: i32,
+    owner: String,
+    name: String,
+    /// The names of the gallery and the galleries it is nested in, such as `trips/summer`.
+    path: String,
+    parent_id: Option<i32>,
+    visibility: String,
+    description: Option<String>,
+    /// The name of the image shown for the gallery in listings.
+    cover_image: Option<String>,
+    created: Option<String>,
+    updated: Option<String>,
+    url: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ImageResource {
+    id: i32,
+    gallery_id: i32,
+    owner: String,
+    gallery_name: String,
+    url: String,
+    name: String,
+    title: Option<String>,
+    caption: Option<String>,
+    alt_text: Option<String>,
+    size: i64,
+    width: Option<u32>,
+    height: Option<u32>,
+    taken: Option<String>,
+    uploaded: Option<String>,
+    labels: Vec<String>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct LabelResource {
+    id: i32,
+    name: String,
+}
+
+fn user_resource(user_row: &mysql::Row) -> UserResource {
+    let username: String = mysql::from_value(user_row["username"].clone());
+    UserResource {
+        id: mysql::from_value(user_row["id"].clone()),
+        url: format!("/u/{}", username),
+        username,
+    }
+}
+
+fn settings_resource(user_row: &mysql::Row) -> SettingsResource {
+    SettingsResource {
+        user: user_resource(user_row),
+        email: mysql::from_value(user_row["email"].clone()),
+        strip_metadata: mysql::from_value(user_row["strip_metadata"].clone()),
+        keep_original_metadata: mysql::from_value(user_row["keep_original_metadata"].clone()),
+        gallery_order: mysql::from_value(user_row["gallery_order"].clone()),
+        accessibility_strict: mysql::from_value(user_row["accessibility_strict"].clone()),
+    }
+}
+
+fn gallery_resource(gallery: &mysql::Row, owner: &str) -> GalleryResource {
+    let galleryid: i32 = mysql::from_value(gallery["id"].clone());
+    let path = gallery_operations::gallery_path(galleryid);
+    GalleryResource {
+        id: galleryid,
+        owner: String::from(owner),
+        name: mysql::from_value(gallery["name"].clone()),
+        url: format!("/u/{}/{}", owner, path),
+        path,
+        parent_id: mysql::from_value(gallery["parent"].clone()),
+        visibility: mysql::from_value(gallery["visibility"].clone()),
+        description: mysql::from_value(gallery["description"].clone()),
+        cover_image: gallery_operations::gallery_cover(gallery),
+        created: mysql_init::datetime_string(&gallery["created"]),
+        updated: mysql_init::datetime_string(&gallery["updated"]),
+    }
+}
+
+fn image_resource(image: &mysql::Row, owner: &str, gallery_path: &str) -> ImageResource {
+    let imageid: i32 = mysql::from_value(image["id"].clone());
+    let name: String = mysql::from_value(image["name"].clone());
+    ImageResource {
+        id: imageid,
+        gallery_id: mysql::from_value(image["gallery"].clone()),
+        owner: String::from(owner),
+        gallery_name: String::from(gallery_path),
+        url: format!("/u/{}/{}/{}", owner, gallery_path, name),
+        name,
+        title: mysql::from_value(image["title"].clone()),
+        caption: mysql::from_value(image["caption"].clone()),
+        alt_text: mysql::from_value(image["alt_text"].clone()),
+        size: mysql::from_value(image["size"].clone()),
+        width: mysql::from_value(image["width"].clone()),
+        height: mysql::from_value(image["height"].clone()),
+        taken: mysql_init::datetime_string(&image["taken"]),
+        uploaded: mysql_init::datetime_string(&image["uploaded"]),
+        labels: labels::image_labels(imageid),
+    }
+}
+fn gallery_response(viewer: Option<&mysql::Row>, galleryid: i32, status: StatusCode) -> ApiReply<GalleryResource> {
+    let (owner, gallery) = find_viewable_gallery_by_id(viewer, galleryid).ok_or_else(not_found)?;
+    let owner: String = mysql::from_value(owner["username"].clone());
+    reply(status, gallery_resource(&gallery, &owner))
+}
+
+/// The path of one of the user's own galleries. Galleries the user can see but doesn't own are
+/// forbidden and all others are not found.
+fn owned_gallery_path(user_row: &mysql::Row, galleryid: i32) -> Result<String, ApiError> {
+    match find_viewable_gallery_by_id(Some(user_row), galleryid) {
+        Some((owner, _)) if gallery_operations::is_same_user(Some(user_row), &owner) => {
+            Ok(gallery_operations::gallery_path(galleryid))
+        }
+        Some(_) => Err(error(StatusCode::FORBIDDEN, "Only the owner can change a gallery")),
+        None => Err(not_found()),
+    }
+}
+
+/// A live image in a gallery the viewer may see, with its owner's username and gallery path.
+fn viewable_image(viewer: Option<&mysql::Row>, imageid: i32) -> Option<(mysql::Row, String, String)> {
+    let image: mysql::Row = mysql_init::get_conn()
+        .exec_first(
+            "SELECT * FROM images WHERE id=:imageid AND deleted IS NULL",
+            params!("imageid"=>imageid),
+        )
+        .expect("Failed to find image")?;
+    let galleryid: i32 = mysql::from_value(image["gallery"].clone());
+    let (owner, _) = find_viewable_gallery_by_id(viewer, galleryid)?;
+    Some((image, mysql::from_value(owner["username"].clone()), gallery_operations::gallery_path(galleryid)))
+}
+
+fn image_response(viewer: Option<&mysql::Row>, imageid: i32, status: StatusCode) -> ApiReply<ImageResource> {
+    let (image, owner, gallery_path) = viewable_image(viewer, imageid).ok_or_else(not_found)?;
+    reply(status, image_resource(&image, &owner, &gallery_path))
+}
+
+/// Checks that the user may edit the image. Images the user can only see are forbidden.
+fn check_editable_image(user_row: &mysql::Row, imageid: i32) -> Result<OwnedImage, ApiError> {
+    match image_operations::find_image_by_id(user_row, imageid) {
+        Some(image) => Ok(image),
+        None if viewable_image(Some(user_row), imageid).is_some() => {
+            Err(error(StatusCode::FORBIDDEN, "Only the owner and editors can change an image"))
+        }
+        None => Err(not_found()),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/sessions",
+    request_body = Login,
+    responses(
+        (status = 201, description = "Session started", body = SessionResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Invalid email or password", body = ErrorBody),
+    )
+)]
+async fn create_session(body: Body<Login>) -> ApiReply<SessionResource> {
+    let login = body.parse(&[]).await?;
+    let token = crate::start_session(&login)
+        .ok_or_else(|| error(StatusCode::UNAUTHORIZED, "Invalid email or password"))?;
+    reply(
+        StatusCode::CREATED,
+        SessionResource {
+            token,
+            token_type: String::from("Bearer"),
+        },
+    )
+}
+
+#[utoipa::path(
+    delete,
+    path = "/sessions/current",
+    responses(
+        (status = 204, description = "Done"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn end_session(hr: HttpRequest) -> ApiResult {
+    authenticated(&hr)?;
+    mysql_init::get_conn()
+        .exec_drop(
+            "DELETE FROM activesessions WHERE id=:id",
+            params!("id"=>api_tokens::bearer(&hr)),
+        )
+        .expect("Failed to end session");
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    post,
+    path = "/users",
+    request_body = Signup,
+    responses(
+        (status = 201, description = "User created", body = UserResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 409, description = "Conflicts with an existing entry", body = ErrorBody),
+    )
+)]
+async fn create_user(body: Body<Signup>) -> ApiReply<UserResource> {
+    let signup = body.parse(&[]).await?;
+    match crate::create_user(&signup) {
+        Some(true) => {
+            let user_row = signup
+                .get_username()
+                .and_then(|username| gallery_operations::find_user(&username))
+                .ok_or_else(|| error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create user"))?;
+            reply(StatusCode::CREATED, user_resource(&user_row))
+        }
+        Some(false) => Err(error(StatusCode::CONFLICT, "Username or email address already taken")),
+        None => Err(error(StatusCode::BAD_REQUEST, "Invalid email, password or username")),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/me",
+    responses(
+        (status = 200, description = "The signed in user", body = SettingsResource),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn get_me(hr: HttpRequest) -> ApiReply<SettingsResource> {
+    reply(StatusCode::OK, settings_resource(&authenticated(&hr)?))
+}
+
+#[utoipa::path(
+    patch,
+    path = "/users/me",
+    request_body = SettingsUpdate,
+    responses(
+        (status = 200, description = "Settings updated", body = SettingsResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn update_me(hr: HttpRequest, body: Body<SettingsUpdate>) -> ApiReply<SettingsResource> {
+    let user_row = authenticated(&hr)?;
+    let settings = body.parse(&[("id", json!(api_tokens::bearer(&hr)))]).await?;
+    let result = crate::update_settings(&user_row, &settings);
+    if result["success"] != json!(true) {
+        return Err(error(
+            StatusCode::BAD_REQUEST,
+            result["message"].as_str().unwrap_or("Invalid settings"),
+        ));
+    }
+    get_me(hr).await
+}
+
+/// The signed in user's API tokens, newest first.
+#[utoipa::path(
+    get,
+    path = "/users/me/tokens",
+    responses(
+        (status = 200, description = "The user's API tokens", body = Collection<ApiToken>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn list_tokens(hr: HttpRequest) -> ApiReply<Collection<ApiToken>> {
+    let user_row = authorized(&hr, Scope::Admin)?;
+    let items = api_tokens::list_tokens(mysql::from_value(user_row["id"].clone()));
+    collection(items, pagination::Cursors::default())
+}
+
+/// Creates an API token. The response is the only time the token itself is shown.
+#[utoipa::path(
+    post,
+    path = "/users/me/tokens",
+    request_body = TokenCreate,
+    responses(
+        (status = 201, description = "Token created", body = CreatedToken),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn create_token(hr: HttpRequest, body: Body<TokenCreate>) -> ApiReply<CreatedToken> {
+    let user_row = authorized(&hr, Scope::Admin)?;
+    let request = body.parse(&[("id", json!(api_tokens::bearer(&hr)))]).await?;
+    let created = api_tokens::create_token(&user_row, &request)
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Invalid token name, scopes or expiry"))?;
+    reply(StatusCode::CREATED, created)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/users/me/tokens/{id}",
+    params(("id" = i32, Path, description = "Token id")),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this token", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn revoke_token(path: web::Path<i32>, hr: HttpRequest) -> ApiResult {
+    let user_row = authorized(&hr, Scope::Admin)?;
+    if !api_tokens::revoke_token(mysql::from_value(user_row["id"].clone()), *path) {
+        return Err(not_found());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}",
+    params(("username" = String, Path)),
+    responses(
+        (status = 200, description = "The user", body = UserResource),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn get_user(path: web::Path<String>) -> ApiReply<UserResource> {
+    let user_row = gallery_operations::find_user(&path).ok_or_else(not_found)?;
+    reply(StatusCode::OK, user_resource(&user_row))
+}
+
+#[utoipa::path(
+    get,
+    path = "/users/{username}/galleries",
+    params(("username" = String, Path), PageQuery),
+    responses(
+        (status = 200, description = "The galleries the viewer may see", body = Collection<GalleryResource>),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn list_user_galleries(
+    path: web::Path<String>,
+    page: web::Query<PageQuery>,
+    hr: HttpRequest,
+) -> ApiReply<Collection<GalleryResource>> {
+    let owner = gallery_operations::find_user(&path).ok_or_else(not_found)?;
+    let username: String = mysql::from_value(owner["username"].clone());
+    let page = user_gallery_rows(viewer(&hr).as_ref(), &owner, page.page_request());
+    let items = page.rows.iter().map(|gallery| gallery_resource(gallery, &username)).collect();
+    collection(items, page.cursors)
+}
+
+#[utoipa::path(
+    post,
+    path = "/galleries",
+    request_body = GalleryCreate,
+    responses(
+        (status = 201, description = "Gallery created", body = GalleryResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 409, description = "Conflicts with an existing entry", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn create_gallery(hr: HttpRequest, body: Body<GalleryCreate>) -> ApiReply<GalleryResource> {
+    let user_row = authenticated(&hr)?;
+    let gallery_create = body.parse(&[("id", json!(api_tokens::bearer(&hr)))]).await?;
+    let gallery_name = gallery_create
+        .get_gallery_name()
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Invalid gallery name"))?;
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if gallery_operations::find_gallery_id(userid, &gallery_name).is_some() {
+        return Err(error(StatusCode::CONFLICT, "Gallery already exists"));
+    }
+    if !crate::create_gallery(&user_row, &gallery_create) {
+        return Err(error(StatusCode::BAD_REQUEST, "Invalid gallery"));
+    }
+    let galleryid = gallery_operations::find_gallery_id(userid, &gallery_name)
+        .ok_or_else(|| error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to create gallery"))?;
+    gallery_response(Some(&user_row), galleryid, StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    get,
+    path = "/galleries/{id}",
+    params(("id" = i32, Path, description = "Gallery id")),
+    responses(
+        (status = 200, description = "The gallery", body = GalleryResource),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn get_gallery(path: web::Path<i32>, hr: HttpRequest) -> ApiReply<GalleryResource> {
+    gallery_response(viewer(&hr).as_ref(), *path, StatusCode::OK)
+}
+
+/// Checks every change before saving any, then applies them in turn: description and cover image,
+/// visibility, name and parent. A request that fails leaves the gallery unchanged.
+#[utoipa::path(
+    patch,
+    path = "/galleries/{id}",
+    params(("id" = i32, Path, description = "Gallery id")),
+    request_body = GalleryPatch,
+    responses(
+        (status = 200, description = "Gallery updated", body = GalleryResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+        (status = 409, description = "Conflicts with an existing entry", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn update_gallery(path: web::Path<i32>, hr: HttpRequest, body: Body<GalleryPatch>) -> ApiReply<GalleryResource> {
+    let user_row = authenticated(&hr)?;
+    let mut gallery_name = owned_gallery_path(&user_row, *path)?;
+    let patch = body.parse(&[]).await?;
+    let visibility = match patch.get_visibility() {
+        Some(name) => Some(
+            gallery_operations::Visibility::from_name(&name)
+                .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Unknown visibility"))?,
+        ),
+        None => None,
+    };
+    let (name, parent, description, cover_image) = match (
+        patch.get_name(),
+        patch.get_parent(),
+        patch.get_description(),
+        patch.get_cover_image(),
+    ) {
+        (Ok(name), Ok(parent), Ok(description), Ok(cover_image)) => (name, parent, description, cover_image),
+        _ => return Err(error(StatusCode::BAD_REQUEST, "Invalid gallery fields")),
+    };
+    if let Some(cover_image) = cover_image.as_deref().filter(|cover_image| !cover_image.is_empty()) {
+        if gallery_operations::find_cover_image(*path, cover_image).is_none() {
+            return Err(error(StatusCode::BAD_REQUEST, "Cover image is not in the gallery"));
+        }
+    }
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let renamed = match &name {
+        Some(name) => gallery_operations::renamed_path(userid, &gallery_name, name)
+            .ok_or_else(|| error(StatusCode::CONFLICT, "A gallery with that name already exists"))?,
+        None => gallery_name.clone(),
+    };
+    if let Some(parent) = &parent {
+        if gallery_operations::move_destination(userid, *path, &renamed, parent.as_deref()).is_none() {
+            return Err(error(StatusCode::CONFLICT, "The gallery can't be moved there"));
+        }
+    }
+    if (description.is_some() || cover_image.is_some())
+        && !gallery_operations::update_gallery(&user_row, &gallery_name, description, cover_image)
+    {
+        return Err(error(StatusCode::BAD_REQUEST, "Cover image is not in the gallery"));
+    }
+    if let Some(visibility) = visibility {
+        gallery_operations::set_visibility(&user_row, &gallery_name, visibility);
+    }
+    if let Some(name) = name {
+        if !gallery_operations::rename_gallery(&user_row, &gallery_name, &name) {
+            return Err(error(StatusCode::CONFLICT, "A gallery with that name already exists"));
+        }
+        gallery_name = gallery_operations::gallery_path(*path);
+    }
+    if let Some(parent) = parent {
+        if !gallery_operations::move_gallery(&user_row, &gallery_name, parent.as_deref()) {
+            return Err(error(StatusCode::CONFLICT, "The gallery can't be moved there"));
+        }
+    }
+    gallery_response(Some(&user_row), *path, StatusCode::OK)
+}
+
+/// The gallery's path, repeated to confirm deleting it along with everything nested in it.
+#[derive(Deserialize)]
+struct DeleteConfirmation {
+    confirm_name: Option<String>,
+}
+
+/// Moves the gallery and everything nested in it to the trash, once `confirm_name` repeats the
+/// gallery's path.
+#[utoipa::path(
+    delete,
+    path = "/galleries/{id}",
+    params(
+        ("id" = i32, Path, description = "Gallery id"),
+        ("confirm_name" = String, Query, description = "The gallery's path, such as `trips/summer`"),
+    ),
+    responses(
+        (status = 204, description = "Done"),
+        (status = 400, description = "confirm_name doesn't match the gallery's path", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn delete_gallery(
+    path: web::Path<i32>,
+    confirmation: web::Query<DeleteConfirmation>,
+    hr: HttpRequest,
+) -> ApiResult {
+    let user_row = authenticated(&hr)?;
+    let gallery_name = owned_gallery_path(&user_row, *path)?;
+    if confirmation.confirm_name.as_deref() != Some(gallery_name.as_str()) {
+        return Err(error(StatusCode::BAD_REQUEST, "confirm_name must match the gallery's path"));
+    }
+    if !gallery_operations::delete_gallery(&user_row, &gallery_name) {
+        return Err(not_found());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The galleries nested directly in a gallery. Visitors only see public ones.
+#[utoipa::path(
+    get,
+    path = "/galleries/{id}/galleries",
+    params(("id" = i32, Path, description = "Gallery id")),
+    responses(
+        (status = 200, description = "The nested galleries", body = Collection<GalleryResource>),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn list_child_galleries(path: web::Path<i32>, hr: HttpRequest) -> ApiReply<Collection<GalleryResource>> {
+    let viewer = viewer(&hr);
+    let (owner, gallery) = find_viewable_gallery_by_id(viewer.as_ref(), *path).ok_or_else(not_found)?;
+    let ownerid: i32 = mysql::from_value(owner["id"].clone());
+    let username: String = mysql::from_value(owner["username"].clone());
+    let gallery_path = gallery_operations::gallery_path(*path);
+    let public_only = !gallery_operations::is_same_user(viewer.as_ref(), &owner)
+        && !gallery_operations::is_member(viewer.as_ref(), &gallery);
+    let items = gallery_operations::child_galleries(&owner, *path, public_only)
+        .into_iter()
+        .filter_map(|child| {
+            let childid = gallery_operations::find_gallery_id(ownerid, &format!("{}/{}", gallery_path, child))?;
+            let (_, child) = find_viewable_gallery_by_id(viewer.as_ref(), childid)?;
+            Some(gallery_resource(&child, &username))
+        })
+        .collect();
+    collection(items, pagination::Cursors::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/galleries/{id}/images",
+    params(("id" = i32, Path, description = "Gallery id"), PageQuery, ImageListing),
+    responses(
+        (status = 200, description = "The gallery's images", body = Collection<ImageResource>),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn list_gallery_images(
+    path: web::Path<i32>,
+    page: web::Query<PageQuery>,
+    listing: web::Query<ImageListing>,
+    hr: HttpRequest,
+) -> ApiReply<Collection<ImageResource>> {
+    let filter = ImageFilter::from_listing(&listing)
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Invalid sort or filter"))?;
+    let (owner, _) = find_viewable_gallery_by_id(viewer(&hr).as_ref(), *path).ok_or_else(not_found)?;
+    let username: String = mysql::from_value(owner["username"].clone());
+    let gallery_path = gallery_operations::gallery_path(*path);
+    let page = gallery_image_rows(*path, &filter, page.page_request());
+    let items = page.rows.iter().map(|image| image_resource(image, &username, &gallery_path)).collect();
+    collection(items, page.cursors)
+}
+
+/// Uploads one image, given as in `/post/image` but without its gallery, which comes from the
+/// URL. Owners and contributors can upload.
+#[utoipa::path(
+    post,
+    path = "/galleries/{id}/images",
+    params(("id" = i32, Path, description = "Gallery id")),
+    request_body = ImageCreate,
+    responses(
+        (status = 201, description = "Image stored", body = ImageResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+        (status = 409, description = "Conflicts with an existing entry", body = ErrorBody),
+        (status = 413, description = "Over the storage quota", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn upload_image(path: web::Path<i32>, hr: HttpRequest, body: Body<ImageCreate>) -> ApiReply<ImageResource> {
+    let user_row = authorized(&hr, Scope::Upload)?;
+    let (owner, _) = find_viewable_gallery_by_id(Some(&user_row), *path).ok_or_else(not_found)?;
+    let gallery_path = gallery_operations::gallery_path(*path);
+    let owner_name: String = mysql::from_value(owner["username"].clone());
+    let extra = [("gallery_name", json!(gallery_path)), ("owner", json!(owner_name))];
+    let image = body.parse_within(usize::MAX, &extra).await?;
+    let status = match store_image(&user_row, image, &|| ()) {
+        Ok(stored) => return image_response(Some(&user_row), stored.id, StatusCode::CREATED),
+        Err(status) => status,
+    };
+    let message = serde_json::to_value(status).expect("Failed to serialize image status");
+    let message = message.as_str().unwrap_or_default();
+    match status {
+        ImageStatus::UnknownGallery => Err(error(StatusCode::FORBIDDEN, message)),
+        ImageStatus::Duplicate => Err(error(StatusCode::CONFLICT, message)),
+        ImageStatus::OverQuota => Err(error(StatusCode::PAYLOAD_TOO_LARGE, message)),
+        _ => Err(error(StatusCode::BAD_REQUEST, message)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/{id}",
+    params(("id" = i32, Path, description = "Image id")),
+    responses(
+        (status = 200, description = "The image", body = ImageResource),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn get_image(path: web::Path<i32>, hr: HttpRequest) -> ApiReply<ImageResource> {
+    image_response(viewer(&hr).as_ref(), *path, StatusCode::OK)
+}
+
+/// Checks every change before saving any, then applies them in turn: title, caption and alt text,
+/// name and gallery. A request that fails leaves the image unchanged.
+#[utoipa::path(
+    patch,
+    path = "/images/{id}",
+    params(("id" = i32, Path, description = "Image id")),
+    request_body = ImagePatch,
+    responses(
+        (status = 200, description = "Image updated", body = ImageResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+        (status = 409, description = "Conflicts with an existing entry", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn update_image(path: web::Path<i32>, hr: HttpRequest, body: Body<ImagePatch>) -> ApiReply<ImageResource> {
+    let user_row = authenticated(&hr)?;
+    let image = check_editable_image(&user_row, *path)?;
+    let patch = body.parse(&[]).await?;
+    let (name, gallery_name, title, caption, alt_text) = match (
+        patch.get_name(),
+        patch.get_gallery_name(),
+        patch.get_title(),
+        patch.get_caption(),
+        patch.get_alt_text(),
+    ) {
+        (Ok(name), Ok(gallery_name), Ok(title), Ok(caption), Ok(alt_text)) => {
+            (name, gallery_name, title, caption, alt_text)
+        }
+        _ => return Err(error(StatusCode::BAD_REQUEST, "Invalid image fields")),
+    };
+    if patch.has_description() && !image_operations::keeps_alt_text(&image, alt_text.as_deref()) {
+        return Err(error(StatusCode::BAD_REQUEST, "Alt text is required"));
+    }
+    if name.as_ref().is_some_and(|name| image_operations::name_taken(image.gallery_id, name)) {
+        return Err(error(StatusCode::CONFLICT, "An image with that name already exists"));
+    }
+    if let Some(gallery_name) = &gallery_name {
+        let image_name = name.as_deref().unwrap_or(&image.name);
+        if image_operations::move_target(&user_row, &image, gallery_name, image_name).is_none() {
+            return Err(error(StatusCode::CONFLICT, "The image can't be moved there"));
+        }
+    }
+    if patch.has_description()
+        && !image_operations::describe_image(&user_row, *path, title, caption, alt_text)
+    {
+        return Err(error(StatusCode::BAD_REQUEST, "Alt text is required"));
+    }
+    if let Some(name) = name {
+        if !image_operations::rename_image(&user_row, *path, &name) {
+            return Err(error(StatusCode::CONFLICT, "An image with that name already exists"));
+        }
+    }
+    if let Some(gallery_name) = gallery_name {
+        if !image_operations::move_image(&user_row, *path, &gallery_name) {
+            return Err(error(StatusCode::CONFLICT, "The image can't be moved there"));
+        }
+    }
+    image_response(Some(&user_row), *path, StatusCode::OK)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/images/{id}",
+    params(("id" = i32, Path, description = "Image id")),
+    responses(
+        (status = 204, description = "Done"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn delete_image(path: web::Path<i32>, hr: HttpRequest) -> ApiResult {
+    let user_row = authenticated(&hr)?;
+    check_editable_image(&user_row, *path)?;
+    if !image_operations::delete_image(&user_row, *path) {
+        return Err(not_found());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[utoipa::path(
+    get,
+    path = "/images/{id}/labels",
+    params(("id" = i32, Path, description = "Image id")),
+    responses(
+        (status = 200, description = "The image's labels", body = Collection<String>),
+        (status = 404, description = "Not found", body = ErrorBody),
+    )
+)]
+async fn list_image_labels(path: web::Path<i32>, hr: HttpRequest) -> ApiReply<Collection<String>> {
+    viewable_image(viewer(&hr).as_ref(), *path).ok_or_else(not_found)?;
+    collection(labels::image_labels(*path), pagination::Cursors::default())
+}
+
+#[utoipa::path(
+    put,
+    path = "/images/{id}/labels/{label}",
+    params(("id" = i32, Path, description = "Image id"), ("label" = String, Path, description = "Label name")),
+    responses(
+        (status = 200, description = "Label added", body = ImageResource),
+        (status = 400, description = "Invalid request", body = ErrorBody),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn add_image_label(path: web::Path<(i32, String)>, hr: HttpRequest) -> ApiReply<ImageResource> {
+    let (imageid, label) = path.into_inner();
+    let user_row = authenticated(&hr)?;
+    check_editable_image(&user_row, imageid)?;
+    let label = union_structs::parse(&union_structs::LABEL_REGEX, &label)
+        .ok_or_else(|| error(StatusCode::BAD_REQUEST, "Invalid label"))?;
+    if !labels::add_label(&user_row, imageid, &label) {
+        return Err(not_found());
+    }
+    image_response(Some(&user_row), imageid, StatusCode::OK)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/images/{id}/labels/{label}",
+    params(("id" = i32, Path, description = "Image id"), ("label" = String, Path, description = "Label name")),
+    responses(
+        (status = 204, description = "Done"),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+        (status = 403, description = "Not allowed for this user", body = ErrorBody),
+        (status = 404, description = "Not found", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn remove_image_label(path: web::Path<(i32, String)>, hr: HttpRequest) -> ApiResult {
+    let (imageid, label) = path.into_inner();
+    let user_row = authenticated(&hr)?;
+    check_editable_image(&user_row, imageid)?;
+    if !labels::remove_label(&user_row, imageid, &label) {
+        return Err(not_found());
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The labels the user has used on their images.
+#[utoipa::path(
+    get,
+    path = "/labels",
+    responses(
+        (status = 200, description = "The user's labels", body = Collection<LabelResource>),
+        (status = 401, description = "Missing or invalid bearer token", body = ErrorBody),
+    ),
+    security(("bearer" = []))
+)]
+async fn list_labels(hr: HttpRequest) -> ApiReply<Collection<LabelResource>> {
+    let user_row = authenticated(&hr)?;
+    let items = labels::list_labels(mysql::from_value(user_row["id"].clone()))
+        .into_iter()
+        .map(|(id, name)| LabelResource { id, name })
+        .collect();
+    collection(items, pagination::Cursors::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+    use futures_util::FutureExt as _;
+    use std::future::Future;
+    use std::panic::AssertUnwindSafe;
+    use utoipa::PartialSchema;
+
+    #[test]
+    fn bodies_get_extra_fields() {
+        let patch: ImagePatch = parse_body(br#"{"title": "Beach"}"#, &[]).unwrap();
+        assert!(patch.has_description());
+        assert_eq!(patch.get_name(), Ok(None));
+        let image: ImageCreate = parse_body(
+            br#"{"image_name": "a.jpg", "image": ""}"#,
+            &[("gallery_name", json!("trips/summer"))],
+        )
+        .unwrap();
+        assert_eq!(image.get_gallery_name(), Some(String::from("trips/summer")));
+        let malformed = parse_body::<ImagePatch>(b"[]", &[]).err().unwrap();
+        assert_eq!(malformed.status_code(), StatusCode::BAD_REQUEST);
+        assert_eq!(malformed.error_response().status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// The JSON schema of a handler argument that is the request body.
+    pub(super) trait RequestBody {
+        fn schema() -> Option<Value> {
+            None
+        }
+    }
+
+    impl<T: PartialSchema> RequestBody for Body<T> {
+        fn schema() -> Option<Value> {
+            Some(serde_json::to_value(T::schema()).unwrap())
+        }
+    }
+
+    impl RequestBody for HttpRequest {}
+    impl<T> RequestBody for web::Path<T> {}
+    impl<T> RequestBody for web::Query<T> {}
+
+    /// The JSON schema of the body a handler returns on success, if it has one.
+    pub(super) trait ResponseBody {
+        fn schema() -> Option<Value> {
+            None
+        }
+    }
+
+    impl<T: PartialSchema> ResponseBody for ApiReply<T> {
+        fn schema() -> Option<Value> {
+            Some(serde_json::to_value(T::schema()).unwrap())
+        }
+    }
+
+    impl ResponseBody for ApiResult {}
+    impl ResponseBody for HttpResponse {}
+
+    /// The body schemas of a handler taking `Args`.
+    pub(super) trait HandlerBodies<Args> {
+        fn bodies() -> (Option<Value>, Option<Value>);
+    }
+
+    macro_rules! handler_bodies {
+        ($($arg:ident),*) => {
+            impl<F, Fut, $($arg: RequestBody,)*> HandlerBodies<($($arg,)*)> for F
+            where
+                F: Fn($($arg),*) -> Fut,
+                Fut: Future,
+                Fut::Output: ResponseBody,
+            {
+                fn bodies() -> (Option<Value>, Option<Value>) {
+                    let request: Option<Value> = None $(.or_else(<$arg as RequestBody>::schema))*;
+                    (request, <Fut::Output as ResponseBody>::schema())
+                }
+            }
+        };
+    }
+
+    handler_bodies!();
+    handler_bodies!(A);
+    handler_bodies!(A, B);
+    handler_bodies!(A, B, C);
+    handler_bodies!(A, B, C, D);
+
+    pub(super) fn bodies<Args, F: HandlerBodies<Args>>(_: F) -> (Option<Value>, Option<Value>) {
+        F::bodies()
+    }
+
+    /// A schema with every reference to a component replaced by the component, since the
+    /// document inlines some schemas that the types refer to.
+    fn inlined(spec: &Value, schema: &Value) -> Value {
+        match schema {
+            Value::Object(fields) => match fields.get("$ref").and_then(Value::as_str) {
+                Some(reference) => match reference.trim_start_matches("#/components/schemas/") {
+                    // A generic collection's item type refers to a string as a component.
+                    "String" => json!({"type": "string"}),
+                    name => inlined(spec, &spec["components"]["schemas"][name]),
+                },
+                None => Value::Object(fields.iter().map(|(key, value)| (key.clone(), inlined(spec, value))).collect()),
+            },
+            Value::Array(values) => Value::Array(values.iter().map(|value| inlined(spec, value)).collect()),
+            _ => schema.clone(),
+        }
+    }
+
+    /// The schema of a documented JSON body.
+    fn documented_schema(spec: &Value, content: &Value) -> Option<Value> {
+        Some(inlined(spec, &content["application/json"]["schema"])).filter(|schema| !schema.is_null())
+    }
+
+    /// Each handler parses and returns the bodies documented for its operation.
+    #[test]
+    fn handlers_match_documented_bodies() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut documented = 0;
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            for (method, operation) in item.as_object().unwrap() {
+                let operation_id = operation["operationId"].as_str().unwrap_or_default();
+                let (request, response) = handler_bodies(operation_id)
+                    .unwrap_or_else(|| panic!("{} {} has no handler", method, path));
+                let (request, response) = (
+                    request.map(|schema| inlined(&spec, &schema)),
+                    response.map(|schema| inlined(&spec, &schema)),
+                );
+                let success = operation["responses"]
+                    .as_object()
+                    .unwrap()
+                    .iter()
+                    .find(|(status, _)| status.starts_with('2'))
+                    .map(|(_, response)| &response["content"]);
+                assert_eq!(
+                    request,
+                    documented_schema(&spec, &operation["requestBody"]["content"]),
+                    "{} {} request body",
+                    method,
+                    path
+                );
+                assert_eq!(
+                    response,
+                    success.and_then(|content| documented_schema(&spec, content)),
+                    "{} {} response body",
+                    method,
+                    path
+                );
+                documented += 1;
+            }
+        }
+        assert!(documented > 20);
+        assert!(serde_json::from_str::<Value>(&OPENAPI_DOCUMENT).is_ok());
+    }
+
+    /// Sends a request through `configure`, and tells whether it reached a handler. Handlers that
+    /// get as far as the database panic in tests, which shows that they were reached.
+    async fn routed(method: &Method, uri: &str) -> bool {
+        let app = init_service(App::new().configure(configure)).await;
+        let request = TestRequest::default().method(method.clone()).uri(uri).to_request();
+        match AssertUnwindSafe(call_service(&app, request)).catch_unwind().await {
+            Ok(response) => {
+                let status = response.status();
+                let body: Value = serde_json::from_slice(&read_body(response).await).unwrap_or_default();
+                status != StatusCode::METHOD_NOT_ALLOWED
+                    && !(status == StatusCode::NOT_FOUND && body["error"] == "Unknown endpoint")
+            }
+            Err(_) => true,
+        }
+    }
+
+    /// Every documented method and path is routed to a handler.
+    #[actix_rt::test]
+    async fn documented_routes_are_routed() {
+        let spec = ApiDoc::openapi();
+        for (path, item) in &spec.paths.paths {
+            let uri = path.replace("{id}", "1").replace("{username}", "someone").replace("{label}", "sea");
+            for (method, _) in operations(item) {
+                assert!(routed(&method, &format!("/api/v1{}", uri)).await, "{} {} is not routed", method, path);
+            }
+        }
+        assert!(!routed(&Method::GET, "/api/v1/unknown").await);
+        let app = init_service(App::new().configure(configure)).await;
+        for (method, uri, status) in [
+            (Method::GET, "/api/v1/openapi.json", StatusCode::OK),
+            (Method::GET, "/api/v1/users/me", StatusCode::UNAUTHORIZED),
+            (Method::DELETE, "/api/v1/sessions/current", StatusCode::UNAUTHORIZED),
+            (Method::DELETE, "/api/v1/images/1/labels/sea", StatusCode::UNAUTHORIZED),
+            (Method::GET, "/api/v1/unknown", StatusCode::NOT_FOUND),
+        ]
+        .iter()
+        {
+            let request = TestRequest::default().method(method.clone()).uri(uri).to_request();
+            assert_eq!(call_service(&app, request).await.status(), *status, "{} {}", method, uri);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Personal API tokens, for scripts and other clients that should not sign in with a password.
+//! Tokens are sent as `Authorization: Bearer pat_...` and only their SHA-256 hash is stored, so a
+//! token can only be seen when it is created. Each token has a name, scopes, an optional expiry
+//! and the time it was last used. Session ids are accepted in the same header and have every
+//! scope. Websockets take tokens in the same header or as the `id` of their operations.
+
+use crate::union_structs::{self, Scope, SessionRequest, TokenCreate, TokenRevoke};
+use crate::{authenticate_with_id, mysql_init, random_id};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use mysql::params;
+use mysql::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use utoipa::ToSchema;
+
+/// A token as listed in the account settings.
+#[derive(Serialize, ToSchema)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires: Option<String>,
+    pub last_used: Option<String>,
+    pub created: Option<String>,
+}
+
+impl ApiToken {
+    fn from_row(row: &mysql::Row) -> Self {
+        ApiToken {
+            id: mysql::from_value(row["id"].clone()),
+            name: mysql::from_value(row["name"].clone()),
+            scopes: parse_scopes(&mysql::from_value::<String>(row["scopes"].clone())),
+            expires: mysql_init::datetime_string(&row["expires"]),
+            last_used: mysql_init::datetime_string(&row["last_used"]),
+            created: mysql_init::datetime_string(&row["created"]),
+        }
+    }
+}
+
+/// A token that was just created, with the secret to send as the bearer token.
+#[derive(Serialize, ToSchema)]
+pub struct CreatedToken {
+    #[serde(flatten)]
+    pub details: ApiToken,
+    pub token: String,
+}
+
+/// Whether a credential with the given scopes may do what needs `required`.
+pub fn grants(scopes: &[Scope], required: Scope) -> bool {
+    scopes.iter().any(|scope| *scope >= required)
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes.split(',').filter_map(Scope::from_name).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// The credential from an `Authorization: Bearer` header, not yet checked.
+pub fn bearer(hr: &HttpRequest) -> Option<String> {
+    let authorization = hr.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    authorization.strip_prefix("Bearer ").map(String::from)
+}
+
+/// The user behind a session id or an API token and the scopes it has. Sessions have every scope.
+pub fn credential_user(credential: &str) -> Option<(mysql::Row, Vec<Scope>)> {
+    match union_structs::parse(&union_structs::ID_REGEX, credential) {
+        Some(session) => {
+            authenticate_with_id(session).map(|user_row| (user_row, vec![Scope::Admin]))
+        }
+        None => authenticate_token(credential),
+    }
+}
+
+/// The user behind the request's bearer credential and the scopes it has.
+pub fn bearer_user(hr: &HttpRequest) -> Option<(mysql::Row, Vec<Scope>)> {
+    credential_user(&bearer(hr)?)
+}
+
+/// The user behind the request's bearer credential, if it has the scope.
+pub fn authorize(hr: &HttpRequest, scope: Scope) -> Option<mysql::Row> {
+    bearer_user(hr)
+        .filter(|(_, scopes)| grants(scopes, scope))
+        .map(|(user_row, _)| user_row)
+}
+
+/// Looks up an API token that has not expired and records that it was used.
+pub fn authenticate_token(token: &str) -> Option<(mysql::Row, Vec<Scope>)> {
+    let token = union_structs::parse(&union_structs::API_TOKEN_REGEX, token)?;
+    let mut conn = mysql_init::get_conn();
+    let token_row: mysql::Row = conn
+        .exec_first(
+            "SELECT id, user, scopes FROM apitokens WHERE token_hash=:hash AND (expires IS NULL OR expires > NOW())",
+            params!("hash"=>hash_token(&token)),
+        )
+        .expect("Failed to query API token")?;
+    let tokenid: i32 = mysql::from_value(token_row["id"].clone());
+    conn.exec_drop(
+        "UPDATE apitokens SET last_used=NOW() WHERE id=:tokenid",
+        params!("tokenid"=>tokenid),
+    )
+    .expect("Failed to record API token use");
+    let userid: i32 = mysql::from_value(token_row["user"].clone());
+    let user_row: mysql::Row = conn
+        .exec_first(
+            "SELECT * FROM users WHERE id=:userid",
+            params!("userid"=>userid),
+        )
+        .expect("Failed to query API token owner")?;
+    let scopes = parse_scopes(&mysql::from_value::<String>(token_row["scopes"].clone()));
+    Some((user_row, scopes))
+}
+
+/// Creates a token for the user, or returns None if the request is invalid.
+pub fn create_token(user_row: &mysql::Row, request: &TokenCreate) -> Option<CreatedToken> {
+    let (name, scopes, expires) = match (
+        request.get_name(),
+        request.get_scopes(),
+        request.get_expires(),
+    ) {
+        (Some(name), Some(scopes), Ok(expires)) => (name, scopes, expires),
+        _ => return None,
+    };
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let token = format!("pat_{}", random_id(60));
+    let scope_names: Vec<&str> = scopes.iter().map(Scope::name).collect();
+    let mut conn = mysql_init::get_conn();
+    conn.exec_drop(
+        "INSERT INTO apitokens(user, name, token_hash, scopes, expires) VALUES (:userid, :name, :hash, :scopes, :expires)",
+        params!(
+            "userid"=>userid,
+            "name"=>&name,
+            "hash"=>hash_token(&token),
+            "scopes"=>scope_names.join(","),
+            "expires"=>&expires,
+        ),
+    )
+    .expect("Failed to create API token");
+    let tokenid = conn.last_insert_id() as i32;
+    let row: mysql::Row = conn
+        .exec_first(
+            "SELECT * FROM apitokens WHERE id=:tokenid",
+            params!("tokenid"=>tokenid),
+        )
+        .expect("Failed to query API token")?;
+    Some(CreatedToken {
+        details: ApiToken::from_row(&row),
+        token,
+    })
+}
+
+/// The user's tokens, newest first.
+pub fn list_tokens(userid: i32) -> Vec<ApiToken> {
+    mysql_init::get_conn()
+        .exec_map(
+            "SELECT * FROM apitokens WHERE user=:userid ORDER BY created DESC, id DESC",
+            params!("userid"=>userid),
+            |row: mysql::Row| ApiToken::from_row(&row),
+        )
+        .expect("Failed to list API tokens")
+}
+
+/// Deletes one of the user's tokens. Returns false if they have no such token.
+pub fn revoke_token(userid: i32, tokenid: i32) -> bool {
+    let mut conn = mysql_init::get_conn();
+    conn.exec_drop(
+        "DELETE FROM apitokens WHERE id=:tokenid AND user=:userid",
+        params!("tokenid"=>tokenid, "userid"=>userid),
+    )
+    .expect("Failed to revoke API token");
+    conn.affected_rows() == 1
+}
+
+pub fn handle_token_creation(json: Value) -> Value {
+    let request: TokenCreate = match serde_json::from_value(json) {
+        Ok(request) => request,
+        Err(_) => return json!({"success": false}),
+    };
+    match request
+        .get_id()
+        .and_then(authenticate_with_id)
+        .and_then(|user_row| create_token(&user_row, &request))
+    {
+        Some(created) => {
+            json!({"success": true, "token": created.token, "details": created.details})
+        }
+        None => json!({"success": false}),
+    }
+}
+
+pub fn handle_token_list(json: Value) -> Value {
+    let user_row = match serde_json::from_value::<SessionRequest>(json)
+        .ok()
+        .and_then(|request| request.get_id())
+        .and_then(authenticate_with_id)
+    {
+        Some(user_row) => user_row,
+        None => return json!({"success": false}),
+    };
+    let tokens = list_tokens(mysql::from_value(user_row["id"].clone()));
+    json!({"success": true, "tokens": tokens})
+}
+
+pub fn handle_token_revocation(json: Value) -> Value {
+    let revoke: TokenRevoke = match serde_json::from_value(json) {
+        Ok(revoke) => revoke,
+        Err(_) => return json!({"success": false}),
+    };
+    match revoke.get_id().and_then(authenticate_with_id) {
+        Some(user_row) => json!({
+            "success": revoke_token(mysql::from_value(user_row["id"].clone()), revoke.get_token_id())
+        }),
+        None => json!({"success": false}),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn bearer_credentials() {
+        let token = format!("pat_{}", "a".repeat(60));
+        let request = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+            .to_http_request();
+        assert_eq!(bearer(&request), Some(token.clone()));
+        assert!(union_structs::parse(&union_structs::API_TOKEN_REGEX, &token).is_some());
+        let basic = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, format!("Basic {}", token)))
+            .to_http_request();
+        assert_eq!(bearer(&basic), None);
+        assert_eq!(bearer(&TestRequest::default().to_http_request()), None);
+        assert!(union_structs::parse(&union_structs::API_TOKEN_REGEX, &"a".repeat(64)).is_none());
+    }
+
+    #[test]
+    fn scopes() {
+        assert_eq!(parse_scopes("read,admin"), vec![Scope::Read, Scope::Admin]);
+        assert!(parse_scopes("").is_empty());
+        assert!(grants(&[Scope::Upload], Scope::Read));
+        assert!(grants(&[Scope::Upload], Scope::Upload));
+        assert!(!grants(&[Scope::Upload], Scope::Admin));
+        assert!(grants(&[Scope::Read, Scope::Admin], Scope::Upload));
+        assert!(!grants(&[], Scope::Read));
+        assert_eq!(hash_token("pat_a").len(), 64);
+        assert_eq!(hash_token("pat_a"), hash_token("pat_a"));
+    }
+}
@@ -0,0 +1,316 @@
+//! The websocket operations, by name. Legacy sockets at `/ws/{name}` run the one operation in
+//! their url for every message. Sockets at `/ws` send envelopes `{"id", "op", "payload"}` and
+//! get back `{"id", "op", "payload"}` with the same `id` and the operation's result as payload,
+//! so one socket can run any number of operations at once. Multiplexed sockets can also
+//! `subscribe` to a gallery, or to all of the user's galleries, and are then sent
+//! `{"id": null, "op": "event", "payload": event}` for every change to their images. Account
+//! subscriptions also get the progress of the user's uploads through `/post/image`. Operations
+//! run as a session or as an API token, whose scopes limit which operations it can run.
+
+use crate::events::{Broker, Event, Subscribe, Topic, Unsubscribe};
+use crate::union_structs::{self, Envelope, Scope, Subscription};
+use crate::{api_tokens, gallery_members, gallery_operations, image_operations, sharing, trash};
+use actix::{Recipient, SystemService};
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+type Operation = fn(Value) -> Value;
+
+/// Operations that never run with the socket's session and need an explicit `id` in the payload,
+/// because a session taken from a cookie alone must not be enough to mint or revoke credentials.
+const EXPLICIT_SESSION_OPERATIONS: [&str; 3] = ["createtoken", "listtokens", "revoketoken"];
+
+lazy_static! {
+    /// Each operation with the scope an API token needs for it: `read` for those that only list,
+    /// and `admin` for changes. `login` and `signup` take no credential.
+    static ref OPERATIONS: HashMap<&'static str, (Scope, Operation)> = {
+        let operations: [(&'static str, Scope, Operation); 28] = [
+            ("login", Scope::Read, crate::handle_login),
+            ("signup", Scope::Read, crate::handle_signup),
+            ("creategallery", Scope::Admin, crate::handle_gallery_creation),
+            ("settings", Scope::Admin, crate::handle_settings),
+            ("rotateimage", Scope::Admin, crate::handle_image_rotation),
+            ("deleteimage", Scope::Admin, image_operations::handle_image_deletion),
+            ("renameimage", Scope::Admin, image_operations::handle_image_rename),
+            ("describeimage", Scope::Admin, image_operations::handle_image_description),
+            ("moveimage", Scope::Admin, image_operations::handle_image_move),
+            ("renamegallery", Scope::Admin, gallery_operations::handle_gallery_rename),
+            ("deletegallery", Scope::Admin, gallery_operations::handle_gallery_deletion),
+            ("setvisibility", Scope::Admin, gallery_operations::handle_gallery_visibility),
+            ("updategallery", Scope::Admin, gallery_operations::handle_gallery_update),
+            ("ordergalleries", Scope::Admin, gallery_operations::handle_gallery_reorder),
+            ("movegallery", Scope::Admin, gallery_operations::handle_gallery_move),
+            ("invitemember", Scope::Admin, gallery_members::handle_member_invite),
+            ("respondinvite", Scope::Admin, gallery_members::handle_invitation_response),
+            ("removemember", Scope::Admin, gallery_members::handle_member_removal),
+            ("memberships", Scope::Read, gallery_members::handle_membership_list),
+            ("createshare", Scope::Admin, sharing::handle_share_creation),
+            ("listshares", Scope::Read, sharing::handle_share_list),
+            ("revokeshare", Scope::Admin, sharing::handle_share_revocation),
+            ("createtoken", Scope::Admin, api_tokens::handle_token_creation),
+            ("listtokens", Scope::Read, api_tokens::handle_token_list),
+            ("revoketoken", Scope::Admin, api_tokens::handle_token_revocation),
+            ("trash", Scope::Read, trash::handle_trash_list),
+            ("restore", Scope::Admin, trash::handle_trash_restore),
+            ("purge", Scope::Admin, trash::handle_trash_purge),
+        ];
+        operations.iter().map(|&(op, scope, operation)| (op, (scope, operation))).collect()
+    };
+}
+
+/// Runs the named operation, or returns None if there is no such operation. An API token as the
+/// payload's `id` must have the operation's scope, and can't be used for the token operations.
+pub fn dispatch(op: &str, payload: Value) -> Option<Value> {
+    let (scope, operation) = OPERATIONS.get(op)?;
+    if let Some(token) = payload["id"].as_str().filter(|id| union_structs::API_TOKEN_REGEX.is_match(id)) {
+        if EXPLICIT_SESSION_OPERATIONS.contains(&op) {
+            return Some(json!({"success": false, "message": "Tokens can only be managed with a session id"}));
+        }
+        if let Some((_, scopes)) = api_tokens::authenticate_token(token) {
+            if !api_tokens::grants(&scopes, *scope) {
+                let message = format!("The token needs the {} scope", scope.name());
+                return Some(json!({"success": false, "message": message}));
+            }
+        }
+    }
+    Some(operation(payload))
+}
+
+/// Whether a websocket upgrade comes from one of the site's own pages, or from a client that is
+/// not a browser and so sends no `Origin`. Browsers send the `id` cookie along with upgrades from
+/// any site, so sockets opened by other sites must be refused.
+pub fn same_origin(req: &HttpRequest) -> bool {
+    match req.headers().get(header::ORIGIN) {
+        Some(origin) => origin
+            .to_str()
+            .ok()
+            .and_then(|origin| origin.split_once("://"))
+            .is_some_and(|(_, host)| host.eq_ignore_ascii_case(req.connection_info().host())),
+        None => true,
+    }
+}
+
+/// The state of a multiplexed socket: the session id or API token it has signed in with, if any,
+/// and what it has subscribed to with which of them.
+#[derive(Default)]
+pub struct Connection {
+    session: Option<String>,
+    subscriber: Option<Recipient<Event>>,
+    subscriptions: Vec<(Topic, String)>,
+}
+
+impl Connection {
+    /// A connection signed in with the given session id or API token, if it is still valid.
+    pub fn new(session: Option<String>) -> Self {
+        Connection {
+            session: session.filter(|id| api_tokens::credential_user(id).is_some()),
+            ..Connection::default()
+        }
+    }
+    /// Sets where events for the socket's subscriptions are sent.
+    pub fn attach(&mut self, subscriber: Recipient<Event>) {
+        self.subscriber = Some(subscriber);
+    }
+    /// Ends all of the socket's subscriptions.
+    pub fn close(&mut self) {
+        if let Some(subscriber) = &self.subscriber {
+            if !self.subscriptions.is_empty() {
+                Broker::from_registry().do_send(Unsubscribe {
+                    topic: None,
+                    recipient: subscriber.clone(),
+                });
+                self.subscriptions.clear();
+            }
+        }
+    }
+    /// Handles one envelope and returns the reply. Payloads without an `id` run with the socket's
+    /// session, which comes from the `id` cookie or a bearer token when the socket is opened, a
+    /// successful `login` or `authenticate` with `{"id": session}` or `{"id": token}`.
+    pub fn handle(&mut self, text: &str) -> Value {
+        let envelope: Envelope = match serde_json::from_str(text) {
+            Ok(envelope) => envelope,
+            Err(_) => {
+                return json!({
+                    "id": Value::Null,
+                    "payload": {"success": false, "message": "Malformed envelope"}
+                })
+            }
+        };
+        let op = envelope.get_op();
+        let payload = self.with_session(&op, envelope.get_payload());
+        let result = match op.as_str() {
+            "authenticate" => self.authenticate(&payload),
+            "logout" => {
+                self.session = None;
+                self.close();
+                json!({"success": true})
+            }
+            "subscribe" => self.subscribe(&payload, true),
+            "unsubscribe" => self.subscribe(&payload, false),
+            _ => dispatch(&op, payload)
+                .unwrap_or_else(|| json!({"success": false, "message": "Unknown operation"})),
+        };
+        if op == "login" && result["success"] == json!(true) {
+            self.session = result["id"].as_str().map(String::from);
+        }
+        json!({"id": envelope.get_request_id(), "op": op, "payload": result})
+    }
+    fn authenticate(&mut self, payload: &Value) -> Value {
+        match payload["id"].as_str().and_then(api_tokens::credential_user) {
+            Some((user_row, _)) => {
+                self.session = payload["id"].as_str().map(String::from);
+                let username: String = mysql::from_value(user_row["username"].clone());
+                json!({"success": true, "username": username})
+            }
+            None => json!({"success": false}),
+        }
+    }
+    /// Subscribes to, or unsubscribes from, a gallery the user may see or their own account.
+    fn subscribe(&mut self, payload: &Value, subscribe: bool) -> Value {
+        let subscription: Subscription = match serde_json::from_value(payload.clone()) {
+            Ok(subscription) => subscription,
+            Err(_) => return json!({"success": false}),
+        };
+        let (session, subscriber) = match (subscription.get_id(), &self.subscriber) {
+            (Some(session), Some(subscriber)) => (session, subscriber.clone()),
+            _ => return json!({"success": false}),
+        };
+        let user_row = match reader(&session) {
+            Some(user_row) => user_row,
+            None => return json!({"success": false}),
+        };
+        let topic = match subscription.get_gallery_id() {
+            Some(galleryid) => Topic::Gallery(galleryid),
+            None => Topic::Account(mysql::from_value(user_row["id"].clone())),
+        };
+        if subscribe {
+            if !may_see(&user_row, topic) {
+                return json!({"success": false, "message": "Unknown gallery"});
+            }
+            Broker::from_registry().do_send(Subscribe {
+                topic,
+                recipient: subscriber,
+            });
+            self.subscriptions.retain(|(subscribed, _)| *subscribed != topic);
+            self.subscriptions.push((topic, session));
+        } else {
+            Broker::from_registry().do_send(Unsubscribe {
+                topic: Some(topic),
+                recipient: subscriber,
+            });
+            self.subscriptions.retain(|(subscribed, _)| *subscribed != topic);
+        }
+        json!({"success": true})
+    }
+    /// Whether the socket may be sent the event. Access is checked again for every event, as the
+    /// session may have ended, the gallery may have become private or the member may have been
+    /// removed since the socket subscribed.
+    pub fn may_receive(&self, event: &Event) -> bool {
+        event.topics().into_iter().any(|topic| {
+            self.subscriptions
+                .iter()
+                .filter(|(subscribed, _)| *subscribed == topic)
+                .filter_map(|(_, session)| reader(session))
+                .any(|user_row| may_see(&user_row, topic))
+        })
+    }
+    /// Fills in the socket's session as the payload's `id` if it has none, unless the operation
+    /// needs an explicit session.
+    pub fn with_session(&self, op: &str, mut payload: Value) -> Value {
+        if EXPLICIT_SESSION_OPERATIONS.contains(&op) {
+            return payload;
+        }
+        if let (Some(session), Some(object)) = (&self.session, payload.as_object_mut()) {
+            object.entry("id").or_insert_with(|| json!(session));
+        }
+        payload
+    }
+}
+
+/// The user behind a session id, or an API token with the `read` scope, for subscriptions.
+fn reader(credential: &str) -> Option<mysql::Row> {
+    api_tokens::credential_user(credential)
+        .filter(|(_, scopes)| api_tokens::grants(scopes, Scope::Read))
+        .map(|(user_row, _)| user_row)
+}
+
+/// Whether the user may see what is published to the topic: a gallery they can view or their own
+/// account.
+fn may_see(user_row: &mysql::Row, topic: Topic) -> bool {
+    match topic {
+        Topic::Gallery(galleryid) => {
+            gallery_operations::find_viewable_gallery_by_id(Some(user_row), galleryid).is_some()
+        }
+        Topic::Account(userid) => mysql::from_value::<i32>(user_row["id"].clone()) == userid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelopes() {
+        let mut connection = Connection::default();
+        let reply = connection.handle(r#"{"id": 7, "op": "nosuchop", "payload": {}}"#);
+        assert_eq!(reply["id"], json!(7));
+        assert_eq!(reply["op"], json!("nosuchop"));
+        assert_eq!(reply["payload"]["message"], json!("Unknown operation"));
+        let reply = connection.handle(r#"{"id": "a", "op": "logout"}"#);
+        assert_eq!(reply["id"], json!("a"));
+        assert_eq!(reply["payload"]["success"], json!(true));
+        assert_eq!(connection.handle("[]")["payload"]["message"], json!("Malformed envelope"));
+        assert!(dispatch("nosuchop", json!({})).is_none());
+        let reply = connection.handle(r#"{"id": 8, "op": "creategallery", "payload": []}"#);
+        assert_eq!(reply["payload"], json!({"success": false}));
+    }
+
+    #[test]
+    fn foreign_origins() {
+        use actix_web::test::TestRequest;
+        let request = |origin: Option<&str>| {
+            let request =
+                TestRequest::default().insert_header((header::HOST, "union.example:8443"));
+            match origin {
+                Some(origin) => request.insert_header((header::ORIGIN, origin)),
+                None => request,
+            }
+            .to_http_request()
+        };
+        assert!(same_origin(&request(Some("https://union.example:8443"))));
+        assert!(same_origin(&request(None)));
+        assert!(!same_origin(&request(Some("https://evil.example"))));
+        assert!(!same_origin(&request(Some("https://union.example"))));
+        assert!(!same_origin(&request(Some("null"))));
+    }
+
+    #[test]
+    fn events_need_a_subscription() {
+        let upload = crate::events::UploadEvent::new(crate::events::UploadStage::Finished, "job", 1);
+        assert!(!Connection::default().may_receive(&Event::from(upload)));
+    }
+
+    #[test]
+    fn session_fills_in_payload_ids() {
+        let mut connection = Connection::default();
+        assert_eq!(connection.with_session("trash", json!({"a": 1})), json!({"a": 1}));
+        connection.session = Some(String::from("session"));
+        assert_eq!(connection.with_session("trash", json!({"a": 1})), json!({"a": 1, "id": "session"}));
+        assert_eq!(connection.with_session("trash", json!({"id": "other"})), json!({"id": "other"}));
+        assert_eq!(connection.with_session("createtoken", json!({"a": 1})), json!({"a": 1}));
+    }
+
+    #[test]
+    fn tokens_cant_manage_tokens() {
+        let token = format!("pat_{}", "a".repeat(60));
+        for op in EXPLICIT_SESSION_OPERATIONS.iter() {
+            let reply = dispatch(op, json!({"id": token, "token_id": 1})).unwrap();
+            assert_eq!(reply["message"], json!("Tokens can only be managed with a session id"));
+        }
+        assert_eq!(OPERATIONS["trash"].0, Scope::Read);
+        assert_eq!(OPERATIONS["deletegallery"].0, Scope::Admin);
+    }
+}
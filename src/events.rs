@@ -0,0 +1,377 @@
+//! Pushes changes to images, and the progress of uploads, to the websockets that have subscribed
+//! to them. Every event is published to the `Broker`, which passes changes to images on to the
+//! subscribers of the image's gallery and of the gallery owner's account, and upload progress on
+//! to the subscribers of the uploader's account.
+
+use crate::image_operations::OwnedImage;
+use crate::union_structs::ImageStatus;
+use actix::prelude::*;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+static BROKER: OnceLock<Addr<Broker>> = OnceLock::new();
+static UPLOAD_JOBS: OnceLock<Mutex<HashMap<String, UploadJob>>> = OnceLock::new();
+
+/// How long the events of an upload job can be polled after its last one.
+const UPLOAD_JOB_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// What a websocket can subscribe to: one gallery, or every gallery of one user.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Gallery(i32),
+    Account(i32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum EventKind {
+    #[serde(rename = "image_added")]
+    Added,
+    #[serde(rename = "image_deleted")]
+    Deleted,
+    #[serde(rename = "image_renamed")]
+    Renamed,
+    #[serde(rename = "image_labelled")]
+    Labelled,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageEvent {
+    pub event: EventKind,
+    pub owner: String,
+    #[serde(skip)]
+    pub owner_id: i32,
+    pub gallery_id: i32,
+    pub gallery_name: String,
+    pub image_id: i32,
+    pub image_name: String,
+    /// The image's name before it was renamed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_name: Option<String>,
+    /// The image's labels after it was labelled or unlabelled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Vec<String>>,
+}
+
+impl ImageEvent {
+    pub fn new(event: EventKind, image: &OwnedImage) -> Self {
+        ImageEvent {
+            event,
+            owner: image.username.clone(),
+            owner_id: image.owner_id,
+            gallery_id: image.gallery_id,
+            gallery_name: image.gallery_name.clone(),
+            image_id: image.id,
+            image_name: image.name.clone(),
+            previous_name: None,
+            labels: None,
+        }
+    }
+}
+
+/// How far one file of an upload job has got.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum UploadStage {
+    #[serde(rename = "upload_received")]
+    Received,
+    #[serde(rename = "upload_decoded")]
+    Decoded,
+    #[serde(rename = "upload_stored")]
+    Stored,
+    #[serde(rename = "upload_renditions_ready")]
+    RenditionsReady,
+    #[serde(rename = "upload_failed")]
+    Failed,
+    /// Every file of the job has been stored or has failed.
+    #[serde(rename = "upload_finished")]
+    Finished,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct UploadEvent {
+    pub event: UploadStage,
+    pub job: String,
+    #[serde(skip)]
+    pub user_id: i32,
+    /// The file's position in the upload, absent when the whole job has finished.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<i32>,
+    /// Why the file failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ImageStatus>,
+}
+
+impl UploadEvent {
+    pub fn new(event: UploadStage, job: &str, user_id: i32) -> Self {
+        UploadEvent {
+            event,
+            job: String::from(job),
+            user_id,
+            index: None,
+            image_name: None,
+            image_id: None,
+            status: None,
+        }
+    }
+}
+
+/// The upload events published for one job, kept for clients that poll instead of subscribing.
+struct UploadJob {
+    user_id: i32,
+    updated: Instant,
+    events: Vec<UploadEvent>,
+}
+
+fn record_upload(event: &UploadEvent) {
+    let mut jobs = UPLOAD_JOBS.get_or_init(Default::default).lock().expect("Upload jobs lock poisoned");
+    jobs.retain(|_, job| job.updated.elapsed() < UPLOAD_JOB_LIFETIME);
+    let job = jobs.entry(event.job.clone()).or_insert_with(|| UploadJob {
+        user_id: event.user_id,
+        updated: Instant::now(),
+        events: Vec::new(),
+    });
+    job.updated = Instant::now();
+    job.events.push(event.clone());
+}
+
+/// The events published so far for one of the user's recent upload jobs, oldest first.
+pub fn upload_job_events(job: &str, user_id: i32) -> Option<Vec<UploadEvent>> {
+    let jobs = UPLOAD_JOBS.get_or_init(Default::default).lock().expect("Upload jobs lock poisoned");
+    jobs.get(job)
+        .filter(|upload_job| upload_job.user_id == user_id)
+        .map(|upload_job| upload_job.events.clone())
+}
+
+#[derive(Message, Clone, Debug, Serialize)]
+#[rtype(result = "()")]
+#[serde(untagged)]
+pub enum Event {
+    Image(ImageEvent),
+    Upload(UploadEvent),
+}
+
+impl Event {
+    pub fn topics(&self) -> Vec<Topic> {
+        match self {
+            Event::Image(image) => vec![
+                Topic::Gallery(image.gallery_id),
+                Topic::Account(image.owner_id),
+            ],
+            Event::Upload(upload) => vec![Topic::Account(upload.user_id)],
+        }
+    }
+}
+
+impl From<ImageEvent> for Event {
+    fn from(event: ImageEvent) -> Self {
+        Event::Image(event)
+    }
+}
+
+impl From<UploadEvent> for Event {
+    fn from(event: UploadEvent) -> Self {
+        Event::Upload(event)
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub topic: Topic,
+    pub recipient: Recipient<Event>,
+}
+
+/// Ends one subscription, or all of the recipient's subscriptions if there is no topic.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
+    pub topic: Option<Topic>,
+    pub recipient: Recipient<Event>,
+}
+
+#[derive(Default)]
+pub struct Broker {
+    subscribers: HashMap<Topic, Vec<Recipient<Event>>>,
+}
+
+impl Actor for Broker {
+    type Context = Context<Self>;
+}
+
+impl Supervised for Broker {}
+
+impl SystemService for Broker {}
+
+impl Handler<Subscribe> for Broker {
+    type Result = ();
+    fn handle(&mut self, subscribe: Subscribe, _: &mut Self::Context) {
+        let subscribers = self.subscribers.entry(subscribe.topic).or_default();
+        if !subscribers.contains(&subscribe.recipient) {
+            subscribers.push(subscribe.recipient);
+        }
+    }
+}
+
+impl Handler<Unsubscribe> for Broker {
+    type Result = ();
+    fn handle(&mut self, unsubscribe: Unsubscribe, _: &mut Self::Context) {
+        for (topic, subscribers) in self.subscribers.iter_mut() {
+            if unsubscribe.topic.is_none() || unsubscribe.topic == Some(*topic) {
+                subscribers.retain(|subscriber| *subscriber != unsubscribe.recipient);
+            }
+        }
+        self.subscribers
+            .retain(|_, subscribers| !subscribers.is_empty());
+    }
+}
+
+impl Handler<Event> for Broker {
+    type Result = ();
+    fn handle(&mut self, event: Event, _: &mut Self::Context) {
+        let mut recipients: Vec<Recipient<Event>> = Vec::new();
+        for topic in event.topics().iter() {
+            if let Some(subscribers) = self.subscribers.get_mut(topic) {
+                subscribers.retain(|subscriber| subscriber.connected());
+                for subscriber in subscribers.iter() {
+                    if !recipients.contains(subscriber) {
+                        recipients.push(subscriber.clone());
+                    }
+                }
+            }
+        }
+        for recipient in recipients {
+            recipient.do_send(event.clone());
+        }
+    }
+}
+
+/// Starts the broker from within the actix system, so that events can then be published from
+/// any thread, including those running blocking work.
+pub fn start() {
+    BROKER.get_or_init(Broker::from_registry);
+}
+
+/// Keeps upload events for `upload_job_events`, then sends the event to its subscribers once
+/// `start` has run. Before that, as in tests, only the upload events are kept.
+pub fn publish(event: impl Into<Event>) {
+    let event = event.into();
+    if let Event::Upload(upload) = &event {
+        record_upload(upload);
+    }
+    if let Some(broker) = BROKER.get() {
+        broker.do_send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Collector {
+        events: Vec<String>,
+    }
+
+    impl Actor for Collector {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<Event> for Collector {
+        type Result = ();
+        fn handle(&mut self, event: Event, _: &mut Self::Context) {
+            self.events.push(serde_json::to_string(&event).unwrap());
+        }
+    }
+
+    #[derive(Message)]
+    #[rtype(result = "Vec<String>")]
+    struct Collected;
+
+    impl Handler<Collected> for Collector {
+        type Result = Vec<String>;
+        fn handle(&mut self, _: Collected, _: &mut Self::Context) -> Vec<String> {
+            self.events.clone()
+        }
+    }
+
+    fn image(gallery_id: i32) -> OwnedImage {
+        OwnedImage {
+            id: 5,
+            name: String::from("beach.jpg"),
+            gallery_id,
+            gallery_name: String::from("trips"),
+            owner_id: 1,
+            username: String::from("someone"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn events_reach_subscribers() {
+        let broker = Broker::default().start();
+        let collector = Collector::default().start();
+        let recipient = collector.clone().recipient();
+        for topic in [Topic::Gallery(2), Topic::Account(1)].iter() {
+            let subscribe = Subscribe {
+                topic: *topic,
+                recipient: recipient.clone(),
+            };
+            broker.send(subscribe).await.unwrap();
+        }
+        let added = ImageEvent::new(EventKind::Added, &image(2));
+        broker.send(Event::from(added)).await.unwrap();
+        let unsubscribe = Unsubscribe {
+            topic: Some(Topic::Account(1)),
+            recipient: recipient.clone(),
+        };
+        broker.send(unsubscribe).await.unwrap();
+        let elsewhere = ImageEvent::new(EventKind::Deleted, &image(3));
+        broker.send(Event::from(elsewhere)).await.unwrap();
+        let mut renamed = ImageEvent::new(EventKind::Renamed, &image(2));
+        renamed.previous_name = Some(String::from("sea.jpg"));
+        broker.send(Event::from(renamed)).await.unwrap();
+        broker
+            .send(Subscribe {
+                topic: Topic::Account(1),
+                recipient,
+            })
+            .await
+            .unwrap();
+        let mut failed = UploadEvent::new(UploadStage::Failed, "job", 1);
+        failed.index = Some(0);
+        failed.status = Some(ImageStatus::Duplicate);
+        broker.send(Event::from(failed)).await.unwrap();
+        let other_user = UploadEvent::new(UploadStage::Finished, "job", 4);
+        broker.send(Event::from(other_user)).await.unwrap();
+        let events = collector.send(Collected).await.unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            r#"{"event":"image_added","owner":"someone","gallery_id":2,"gallery_name":"trips","image_id":5,"image_name":"beach.jpg"}"#
+        );
+        assert!(events[1].contains(r#""event":"image_renamed""#));
+        assert!(events[1].contains(r#""previous_name":"sea.jpg""#));
+        assert_eq!(
+            events[2],
+            r#"{"event":"upload_failed","job":"job","index":0,"status":"duplicate"}"#
+        );
+    }
+
+    #[test]
+    fn upload_jobs_can_be_polled() {
+        let mut failed = UploadEvent::new(UploadStage::Failed, "polled", 1);
+        failed.status = Some(ImageStatus::OverQuota);
+        publish(failed);
+        publish(UploadEvent::new(UploadStage::Finished, "polled", 1));
+        let events = upload_job_events("polled", 1).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].status, Some(ImageStatus::OverQuota));
+        assert_eq!(events[1].event, UploadStage::Finished);
+        assert!(upload_job_events("polled", 2).is_none());
+        assert!(upload_job_events("unknown", 1).is_none());
+    }
+}
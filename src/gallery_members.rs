@@ -0,0 +1,247 @@
+use crate::union_structs::{InvitationResponse, MemberInvite, MemberRemove, SessionRequest};
+use crate::{authenticate_with_id, gallery_operations, mysql_init};
+use mysql::params;
+use mysql::prelude::*;
+use serde_json::{json, Value};
+
+/// What a member may do in a gallery they don't own and in the galleries nested in it. Each role
+/// includes the ones before it: viewers can see the gallery, contributors can also upload to it,
+/// and editors can also rename and delete its images.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Role {
+    Viewer,
+    Contributor,
+    Editor,
+}
+
+impl Role {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "viewer" => Some(Role::Viewer),
+            "contributor" => Some(Role::Contributor),
+            "editor" => Some(Role::Editor),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Contributor => "contributor",
+            Role::Editor => "editor",
+        }
+    }
+}
+
+const ANCESTORS: &str = "WITH RECURSIVE ancestors(id, parent) AS (SELECT id, parent FROM galleries WHERE id=:galleryid UNION ALL SELECT galleries.id, galleries.parent FROM galleries JOIN ancestors ON galleries.id=ancestors.parent)";
+
+/// The role of a user who has accepted an invitation to the gallery or to one it is nested in.
+/// When there are several, the strongest one applies.
+pub fn member_role(userid: i32, galleryid: i32) -> Option<Role> {
+    let roles: Vec<String> = mysql_init::get_conn()
+        .exec(
+            format!(
+                "{} SELECT role FROM gallerymembers WHERE gallery IN (SELECT id FROM ancestors) AND user=:userid AND accepted",
+                ANCESTORS
+            ),
+            params!("galleryid"=>galleryid, "userid"=>userid),
+        )
+        .expect("Failed to query gallery membership");
+    roles
+        .iter()
+        .filter_map(|role| Role::from_name(role))
+        .fold(None, |strongest, role| match strongest {
+            Some(strongest) if strongest >= role => Some(strongest),
+            _ => Some(role),
+        })
+}
+
+/// The accepted members of a gallery, including those of the galleries it is nested in, as
+/// (username, role) pairs.
+pub fn list_members(galleryid: i32) -> Vec<(String, Role)> {
+    let mut members: Vec<(String, Role)> = vec![];
+    mysql_init::get_conn()
+        .exec_map(
+            format!(
+                "{} SELECT users.username, gallerymembers.role FROM gallerymembers JOIN users ON gallerymembers.user=users.id WHERE gallerymembers.gallery IN (SELECT id FROM ancestors) AND gallerymembers.accepted ORDER BY users.username",
+                ANCESTORS
+            ),
+            params!("galleryid"=>galleryid),
+            |(username, role): (String, String)| (username, role),
+        )
+        .expect("Failed to list gallery members")
+        .into_iter()
+        .filter_map(|(username, role)| Role::from_name(&role).map(|role| (username, role)))
+        .for_each(|(username, role)| match members.last_mut() {
+            Some(last) if last.0 == username => last.1 = if last.1 >= role { last.1 } else { role },
+            _ => members.push((username, role)),
+        });
+    members
+}
+
+/// Invites a user to one of the owner's galleries. Inviting someone who is already a member
+/// changes their role instead.
+pub fn invite_member(owner_row: &mysql::Row, gallery_name: &str, username: &str, role: Role) -> bool {
+    let ownerid: i32 = mysql::from_value(owner_row["id"].clone());
+    if let (Some(galleryid), Some(invitee)) = (
+        gallery_operations::find_gallery_id(ownerid, gallery_name),
+        gallery_operations::find_user(username),
+    ) {
+        if gallery_operations::is_same_user(Some(owner_row), &invitee) {
+            return false;
+        }
+        let inviteeid: i32 = mysql::from_value(invitee["id"].clone());
+        mysql_init::get_conn()
+            .exec_drop(
+                "INSERT INTO gallerymembers(gallery, user, role) VALUES (:galleryid, :userid, :role) ON DUPLICATE KEY UPDATE role=:role",
+                params!("galleryid"=>galleryid, "userid"=>inviteeid, "role"=>role.name()),
+            )
+            .expect("Failed to invite gallery member");
+        return true;
+    }
+    false
+}
+
+fn find_member_gallery(owner: &str, gallery_name: &str) -> Option<i32> {
+    let owner_row = gallery_operations::find_user(owner)?;
+    gallery_operations::find_gallery_id(mysql::from_value(owner_row["id"].clone()), gallery_name)
+}
+
+/// Accepts or declines a pending invitation. Declining removes it.
+pub fn respond_to_invitation(user_row: &mysql::Row, owner: &str, gallery_name: &str, accept: bool) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if let Some(galleryid) = find_member_gallery(owner, gallery_name) {
+        let query = if accept {
+            "UPDATE gallerymembers SET accepted=TRUE WHERE gallery=:galleryid AND user=:userid AND NOT accepted"
+        } else {
+            "DELETE FROM gallerymembers WHERE gallery=:galleryid AND user=:userid AND NOT accepted"
+        };
+        let mut conn = mysql_init::get_conn();
+        conn.exec_drop(query, params!("galleryid"=>galleryid, "userid"=>userid))
+            .expect("Failed to answer gallery invitation");
+        return conn.affected_rows() == 1;
+    }
+    false
+}
+
+/// Removes a member or pending invitation. Owners can remove anyone from their galleries and
+/// members can remove themselves.
+pub fn remove_member(user_row: &mysql::Row, owner: &str, gallery_name: &str, username: &str) -> bool {
+    let own_username: String = mysql::from_value(user_row["username"].clone());
+    if own_username != username && own_username != owner {
+        return false;
+    }
+    if let (Some(galleryid), Some(member)) = (
+        find_member_gallery(owner, gallery_name),
+        gallery_operations::find_user(username),
+    ) {
+        let memberid: i32 = mysql::from_value(member["id"].clone());
+        let mut conn = mysql_init::get_conn();
+        conn.exec_drop(
+            "DELETE FROM gallerymembers WHERE gallery=:galleryid AND user=:userid",
+            params!("galleryid"=>galleryid, "userid"=>memberid),
+        )
+        .expect("Failed to remove gallery member");
+        return conn.affected_rows() == 1;
+    }
+    false
+}
+
+pub fn handle_member_invite(json: Value) -> Value {
+    let invite: MemberInvite = match serde_json::from_value(json) {
+        Ok(invite) => invite,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Some(username), Some(role)) = (
+        invite.get_id().and_then(authenticate_with_id),
+        invite.get_gallery_name(),
+        invite.get_username(),
+        Role::from_name(&invite.get_role()),
+    ) {
+        return json!({"success": invite_member(&user_row, &gallery_name, &username, role)});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_invitation_response(json: Value) -> Value {
+    let response: InvitationResponse = match serde_json::from_value(json) {
+        Ok(response) => response,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(owner), Some(gallery_name)) = (
+        response.get_id().and_then(authenticate_with_id),
+        response.get_owner(),
+        response.get_gallery_name(),
+    ) {
+        return json!({
+            "success": respond_to_invitation(&user_row, &owner, &gallery_name, response.get_accept())
+        });
+    }
+    json!({"success": false})
+}
+
+pub fn handle_member_removal(json: Value) -> Value {
+    let remove: MemberRemove = match serde_json::from_value(json) {
+        Ok(remove) => remove,
+        Err(_) => return json!({"success": false}),
+    };
+    if let Some(user_row) = remove.get_id().and_then(authenticate_with_id) {
+        let own_username: String = mysql::from_value(user_row["username"].clone());
+        if let (Some(owner), Some(gallery_name), Some(username)) = (
+            remove.get_owner().or(Some(own_username)),
+            remove.get_gallery_name(),
+            remove.get_username(),
+        ) {
+            return json!({"success": remove_member(&user_row, &owner, &gallery_name, &username)});
+        }
+    }
+    json!({"success": false})
+}
+
+/// Lists the galleries the user has been invited to, split into pending invitations and accepted
+/// memberships.
+pub fn handle_membership_list(json: Value) -> Value {
+    let user_row = match serde_json::from_value::<SessionRequest>(json)
+        .ok()
+        .and_then(|request| request.get_id())
+        .and_then(authenticate_with_id)
+    {
+        Some(user_row) => user_row,
+        None => return json!({"success": false}),
+    };
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let mut invitations = vec![];
+    let mut memberships = vec![];
+    mysql_init::get_conn()
+        .exec_map(
+            "SELECT users.username, galleries.id, gallerymembers.role, gallerymembers.accepted FROM gallerymembers JOIN galleries ON gallerymembers.gallery=galleries.id JOIN users ON galleries.user=users.id WHERE gallerymembers.user=:userid AND galleries.deleted IS NULL ORDER BY users.username, galleries.name",
+            params!("userid"=>userid),
+            |(owner, galleryid, role, accepted): (String, i32, String, bool)| {
+                let gallery_name = gallery_operations::gallery_path(galleryid);
+                (json!({"owner": owner, "gallery_name": gallery_name, "role": role}), accepted)
+            },
+        )
+        .expect("Failed to list gallery memberships")
+        .into_iter()
+        .for_each(|(entry, accepted)| {
+            if accepted {
+                memberships.push(entry);
+            } else {
+                invitations.push(entry);
+            }
+        });
+    json!({"success": true, "invitations": invitations, "memberships": memberships})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_include_lower_roles() {
+        assert!(Role::Editor > Role::Contributor);
+        assert!(Role::Contributor > Role::Viewer);
+        assert_eq!(Role::from_name("contributor"), Some(Role::Contributor));
+        assert_eq!(Role::from_name(Role::Editor.name()), Some(Role::Editor));
+        assert_eq!(Role::from_name("owner"), None);
+    }
+}
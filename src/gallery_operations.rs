@@ -0,0 +1,525 @@
+use crate::union_structs::{
+    GalleryDelete, GalleryMove, GalleryRename, GalleryReorder, GalleryUpdate, GalleryVisibility,
+};
+use crate::pagination::Keyset;
+use crate::{authenticate_with_id, gallery_members, image_processing, mysql_init, static_interface};
+use mysql::params;
+use mysql::prelude::*;
+use serde_json::{json, Value};
+
+/// Who can see a gallery besides its owner. Unlisted galleries can be viewed by anyone with the
+/// URL, and public galleries are also listed on the owner's user page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Visibility {
+    Private,
+    Unlisted,
+    Public,
+}
+
+impl Visibility {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "private" => Some(Visibility::Private),
+            "unlisted" => Some(Visibility::Unlisted),
+            "public" => Some(Visibility::Public),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Visibility::Private => "private",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Public => "public",
+        }
+    }
+}
+
+/// How a user's galleries are ordered on their user page.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GalleryOrder {
+    Manual,
+    Created,
+    Updated,
+    Name,
+}
+
+impl GalleryOrder {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "manual" => Some(GalleryOrder::Manual),
+            "created" => Some(GalleryOrder::Created),
+            "updated" => Some(GalleryOrder::Updated),
+            "name" => Some(GalleryOrder::Name),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            GalleryOrder::Manual => "manual",
+            GalleryOrder::Created => "created",
+            GalleryOrder::Updated => "updated",
+            GalleryOrder::Name => "name",
+        }
+    }
+    /// How to sort and page through the galleries table in this order.
+    pub fn keyset(&self) -> Keyset {
+        match self {
+            GalleryOrder::Manual => Keyset::new(&["position", "id"], false),
+            GalleryOrder::Created => Keyset::new(&["created", "id"], true),
+            GalleryOrder::Updated => Keyset::new(&["updated", "id"], true),
+            GalleryOrder::Name => Keyset::new(&["name", "id"], false),
+        }
+    }
+}
+
+pub fn user_gallery_order(user_row: &mysql::Row) -> GalleryOrder {
+    let gallery_order: String = mysql::from_value(user_row["gallery_order"].clone());
+    GalleryOrder::from_name(&gallery_order).unwrap_or(GalleryOrder::Manual)
+}
+
+pub fn find_user(username: &str) -> Option<mysql::Row> {
+    mysql_init::get_conn()
+        .exec_first(
+            "SELECT * FROM users WHERE username=:username",
+            params!("username"=>username),
+        )
+        .expect("Failed to query user")
+}
+
+pub fn is_same_user(viewer: Option<&mysql::Row>, owner: &mysql::Row) -> bool {
+    viewer.is_some_and(|viewer| {
+        mysql::from_value::<i32>(viewer["id"].clone()) == mysql::from_value::<i32>(owner["id"].clone())
+    })
+}
+
+/// Looks up a live gallery that the viewer (None when not logged in) is allowed to see, returning
+/// the owner's row and the gallery's row. Members can see the gallery whatever its visibility.
+pub fn find_viewable_gallery(
+    viewer: Option<&mysql::Row>,
+    username: &str,
+    gallery_name: &str,
+) -> Option<(mysql::Row, mysql::Row)> {
+    let owner = find_user(username)?;
+    let galleryid = find_gallery_id(mysql::from_value(owner["id"].clone()), gallery_name)?;
+    let gallery: mysql::Row = mysql_init::get_conn()
+        .exec_first("SELECT * FROM galleries WHERE id=:galleryid", params!("galleryid"=>galleryid))
+        .expect("Failed to query user gallery")?;
+    let visibility: String = mysql::from_value(gallery["visibility"].clone());
+    match Visibility::from_name(&visibility) {
+        Some(Visibility::Unlisted) | Some(Visibility::Public) => Some((owner, gallery)),
+        _ if is_same_user(viewer, &owner) || is_member(viewer, &gallery) => Some((owner, gallery)),
+        _ => None,
+    }
+}
+
+/// Like `find_viewable_gallery`, for a live gallery given by id.
+pub fn find_viewable_gallery_by_id(
+    viewer: Option<&mysql::Row>,
+    galleryid: i32,
+) -> Option<(mysql::Row, mysql::Row)> {
+    let owner: String = mysql_init::get_conn()
+        .exec_first(
+            "SELECT users.username FROM galleries JOIN users ON galleries.user=users.id WHERE galleries.id=:galleryid AND galleries.deleted IS NULL",
+            params!("galleryid"=>galleryid),
+        )
+        .expect("Failed to find gallery owner")?;
+    find_viewable_gallery(viewer, &owner, &gallery_path(galleryid))
+}
+
+pub fn is_member(viewer: Option<&mysql::Row>, gallery: &mysql::Row) -> bool {
+    viewer.is_some_and(|viewer| {
+        gallery_members::member_role(
+            mysql::from_value(viewer["id"].clone()),
+            mysql::from_value(gallery["id"].clone()),
+        )
+        .is_some()
+    })
+}
+
+pub fn set_visibility(user_row: &mysql::Row, gallery_name: &str, visibility: Visibility) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if let Some(galleryid) = find_gallery_id(userid, gallery_name) {
+        mysql_init::get_conn()
+            .exec_drop(
+                "UPDATE galleries SET visibility=:visibility WHERE id=:galleryid",
+                params!("visibility"=>visibility.name(), "galleryid"=>galleryid),
+            )
+            .expect("Failed to set gallery visibility");
+        return true;
+    }
+    false
+}
+
+/// Finds a live gallery by its path, such as `2022/summer`, where each segment names a gallery
+/// nested in the one before it.
+pub fn find_gallery_id(userid: i32, gallery_path: &str) -> Option<i32> {
+    let mut conn = mysql_init::get_conn();
+    let mut galleryid: Option<i32> = None;
+    for gallery_name in gallery_path.split('/') {
+        galleryid = Some(
+            conn.exec_first(
+                "SELECT id FROM galleries WHERE user=:userid AND name=:galleryname AND parent <=> :parent AND deleted IS NULL",
+                params!("userid"=>userid, "galleryname"=>gallery_name, "parent"=>galleryid),
+            )
+            .expect("Failed to query user gallery")?,
+        );
+    }
+    galleryid
+}
+
+/// Splits a gallery path into the path of its parent, if it has one, and its own name.
+pub fn split_gallery_path(gallery_path: &str) -> (Option<&str>, &str) {
+    match gallery_path.rsplit_once('/') {
+        Some((parent, gallery_name)) => (Some(parent), gallery_name),
+        None => (None, gallery_path),
+    }
+}
+
+fn join_gallery_path(parent: Option<&str>, gallery_name: &str) -> String {
+    match parent {
+        Some(parent) => format!("{}/{}", parent, gallery_name),
+        None => String::from(gallery_name),
+    }
+}
+
+/// The full path of a gallery, following its parents whether or not they are trashed.
+pub fn gallery_path(galleryid: i32) -> String {
+    let names: Vec<String> = mysql_init::get_conn()
+        .exec(
+            "WITH RECURSIVE ancestors(id, parent, name, depth) AS (SELECT id, parent, name, 0 FROM galleries WHERE id=:galleryid UNION ALL SELECT galleries.id, galleries.parent, galleries.name, ancestors.depth+1 FROM galleries JOIN ancestors ON galleries.id=ancestors.parent) SELECT name FROM ancestors ORDER BY depth DESC",
+            params!("galleryid"=>galleryid),
+        )
+        .expect("Failed to query gallery path");
+    names.join("/")
+}
+
+/// The ids of a gallery and every gallery nested under it.
+pub fn subtree_ids(galleryid: i32) -> Vec<i32> {
+    mysql_init::get_conn()
+        .exec(
+            "WITH RECURSIVE subtree(id) AS (SELECT id FROM galleries WHERE id=:galleryid UNION ALL SELECT galleries.id FROM galleries JOIN subtree ON galleries.parent=subtree.id) SELECT id FROM subtree",
+            params!("galleryid"=>galleryid),
+        )
+        .expect("Failed to query gallery subtree")
+}
+
+/// Formats ids for an `IN (...)` clause.
+pub fn id_list(ids: &[i32]) -> String {
+    ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(",")
+}
+
+/// The live galleries directly inside a gallery, in the owner's gallery order. Viewers who are
+/// neither the owner nor a member only see public ones.
+pub fn child_galleries(owner: &mysql::Row, galleryid: i32, public_only: bool) -> Vec<String> {
+    let query = format!(
+        "SELECT name FROM galleries WHERE parent=:galleryid AND deleted IS NULL{} ORDER BY {}",
+        if public_only { " AND visibility='public'" } else { "" },
+        user_gallery_order(owner).keyset().order_clause(),
+    );
+    mysql_init::get_conn()
+        .exec(query, params!("galleryid"=>galleryid))
+        .expect("Failed to query child galleries")
+}
+
+/// Marks a gallery as updated, for ordering galleries by their last change.
+pub fn touch_gallery(galleryid: i32) {
+    mysql_init::get_conn()
+        .exec_drop(
+            "UPDATE galleries SET updated=NOW() WHERE id=:galleryid",
+            params!("galleryid"=>galleryid),
+        )
+        .expect("Failed to touch gallery");
+}
+
+/// The name of the image shown for a gallery: its chosen cover if that is still in the gallery,
+/// otherwise its oldest image.
+pub fn gallery_cover(gallery: &mysql::Row) -> Option<String> {
+    let galleryid: i32 = mysql::from_value(gallery["id"].clone());
+    let cover: Option<i32> = mysql::from_value(gallery["cover"].clone());
+    mysql_init::get_conn()
+        .exec_first(
+            "SELECT name FROM images WHERE gallery=:galleryid AND deleted IS NULL ORDER BY COALESCE(id=:cover, FALSE) DESC, id LIMIT 1",
+            params!("galleryid"=>galleryid, "cover"=>cover),
+        )
+        .expect("Failed to find gallery cover")
+}
+
+/// The live image in the gallery that can be made its cover.
+pub fn find_cover_image(galleryid: i32, image_name: &str) -> Option<i32> {
+    mysql_init::get_conn()
+        .exec_first(
+            "SELECT id FROM images WHERE gallery=:galleryid AND name=:imagename AND deleted IS NULL",
+            params!("galleryid"=>galleryid, "imagename"=>image_name),
+        )
+        .expect("Failed to find cover image")
+}
+
+/// Changes a gallery's description and cover image. None leaves a field unchanged and an empty
+/// string clears it.
+pub fn update_gallery(
+    user_row: &mysql::Row,
+    gallery_name: &str,
+    description: Option<String>,
+    cover_image: Option<String>,
+) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if let Some(galleryid) = find_gallery_id(userid, gallery_name) {
+        let mut conn = mysql_init::get_conn();
+        if let Some(description) = description {
+            conn.exec_drop(
+                "UPDATE galleries SET description=:description, updated=NOW() WHERE id=:galleryid",
+                params!(
+                    "description"=>Some(description).filter(|description| !description.is_empty()),
+                    "galleryid"=>galleryid,
+                ),
+            )
+            .expect("Failed to update gallery description");
+        }
+        if let Some(cover_image) = cover_image {
+            let cover: Option<i32> = if cover_image.is_empty() {
+                None
+            } else {
+                match find_cover_image(galleryid, &cover_image) {
+                    Some(imageid) => Some(imageid),
+                    None => return false,
+                }
+            };
+            conn.exec_drop(
+                "UPDATE galleries SET cover=:cover, updated=NOW() WHERE id=:galleryid",
+                params!("cover"=>cover, "galleryid"=>galleryid),
+            )
+            .expect("Failed to update gallery cover");
+        }
+        return true;
+    }
+    false
+}
+
+/// Sets the manual order of the user's galleries. Galleries left out keep their position after
+/// the listed ones.
+pub fn reorder_galleries(user_row: &mysql::Row, gallery_names: &[String]) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let galleryids: Option<Vec<i32>> = gallery_names
+        .iter()
+        .map(|gallery_name| find_gallery_id(userid, gallery_name))
+        .collect();
+    if let Some(galleryids) = galleryids {
+        let mut conn = mysql_init::get_conn();
+        conn.exec_drop(
+            "UPDATE galleries SET position=position+:count WHERE user=:userid",
+            params!("count"=>galleryids.len(), "userid"=>userid),
+        )
+        .expect("Failed to shift gallery positions");
+        for (position, galleryid) in galleryids.into_iter().enumerate() {
+            conn.exec_drop(
+                "UPDATE galleries SET position=:position WHERE id=:galleryid",
+                params!("position"=>position, "galleryid"=>galleryid),
+            )
+            .expect("Failed to set gallery position");
+        }
+        return true;
+    }
+    false
+}
+
+/// The path a gallery would have after being renamed, or None if its parent already has a gallery
+/// with the new name.
+pub fn renamed_path(userid: i32, gallery_name: &str, new_name: &str) -> Option<String> {
+    let (parent, _) = split_gallery_path(gallery_name);
+    let new_path = join_gallery_path(parent, new_name);
+    if find_gallery_id(userid, &new_path).is_some() {
+        return None;
+    }
+    Some(new_path)
+}
+
+/// Renames a gallery and its directory. Fails if the gallery's parent already has a gallery
+/// with the new name.
+pub fn rename_gallery(user_row: &mysql::Row, gallery_name: &str, new_name: &str) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let username: String = mysql::from_value(user_row["username"].clone());
+    if let Some(galleryid) = find_gallery_id(userid, gallery_name) {
+        let new_path = match renamed_path(userid, gallery_name, new_name) {
+            Some(new_path) => new_path,
+            None => return false,
+        };
+        mysql_init::get_conn()
+            .exec_drop(
+                "UPDATE galleries SET name=:newname, updated=NOW() WHERE id=:galleryid",
+                params!("newname"=>new_name, "galleryid"=>galleryid),
+            )
+            .expect("Failed to rename gallery");
+        static_interface::rename_gallery_dir(&username, gallery_name, &new_path);
+        image_processing::clear_gallery_cache(&username, gallery_name);
+        return true;
+    }
+    false
+}
+
+/// The parent id and path a gallery at `gallery_name` would have if moved under `parent`, or None
+/// if the parent is missing, is the gallery or one nested in it, or already has a gallery with the
+/// same name. The path doesn't have to exist yet, so a rename can be checked together with a move.
+pub fn move_destination(
+    userid: i32,
+    galleryid: i32,
+    gallery_name: &str,
+    parent: Option<&str>,
+) -> Option<(Option<i32>, String)> {
+    let parentid = match parent {
+        Some(parent) => match find_gallery_id(userid, parent) {
+            Some(parentid) if !subtree_ids(galleryid).contains(&parentid) => Some(parentid),
+            _ => return None,
+        },
+        None => None,
+    };
+    let (_, name) = split_gallery_path(gallery_name);
+    let new_path = join_gallery_path(parent, name);
+    if new_path != gallery_name && find_gallery_id(userid, &new_path).is_some() {
+        return None;
+    }
+    Some((parentid, new_path))
+}
+
+/// Moves a gallery, along with everything nested in it, under another of the user's galleries or
+/// to the top level when the parent is None. Fails if that would put the gallery inside itself or
+/// if the new parent already has a gallery with the same name.
+pub fn move_gallery(user_row: &mysql::Row, gallery_name: &str, parent: Option<&str>) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let username: String = mysql::from_value(user_row["username"].clone());
+    let galleryid = match find_gallery_id(userid, gallery_name) {
+        Some(galleryid) => galleryid,
+        None => return false,
+    };
+    let (parentid, new_path) = match move_destination(userid, galleryid, gallery_name, parent) {
+        Some(destination) => destination,
+        None => return false,
+    };
+    if new_path == gallery_name {
+        return true;
+    }
+    let mut conn = mysql_init::get_conn();
+    let position: Option<i32> = conn
+        .exec_first(
+            "SELECT COALESCE(MAX(position)+1, 0) FROM galleries WHERE user=:userid AND parent <=> :parent",
+            params!("userid"=>userid, "parent"=>parentid),
+        )
+        .expect("Failed to find gallery position");
+    conn.exec_drop(
+        "UPDATE galleries SET parent=:parent, position=:position, updated=NOW() WHERE id=:galleryid",
+        params!("parent"=>parentid, "position"=>position.unwrap_or(0), "galleryid"=>galleryid),
+    )
+    .expect("Failed to move gallery");
+    static_interface::rename_gallery_dir(&username, gallery_name, &new_path);
+    image_processing::clear_gallery_cache(&username, gallery_name);
+    true
+}
+
+/// Moves a gallery, the galleries nested in it and all their images to the trash. Everything is
+/// kept until the gallery is purged.
+pub fn delete_gallery(user_row: &mysql::Row, gallery_name: &str) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let username: String = mysql::from_value(user_row["username"].clone());
+    if let Some(galleryid) = find_gallery_id(userid, gallery_name) {
+        mysql_init::get_conn()
+            .query_drop(format!(
+                "UPDATE galleries SET deleted=NOW() WHERE id IN ({}) AND deleted IS NULL",
+                id_list(&subtree_ids(galleryid)),
+            ))
+            .expect("Failed to delete gallery");
+        static_interface::trash_gallery_dir(&username, gallery_name, galleryid);
+        image_processing::clear_gallery_cache(&username, gallery_name);
+        return true;
+    }
+    false
+}
+
+pub fn handle_gallery_rename(json: Value) -> Value {
+    let rename: GalleryRename = match serde_json::from_value(json) {
+        Ok(rename) => rename,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Some(new_name)) = (
+        rename.get_id().and_then(authenticate_with_id),
+        rename.get_gallery_name(),
+        rename.get_new_name(),
+    ) {
+        return json!({"success": rename_gallery(&user_row, &gallery_name, &new_name)});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_gallery_visibility(json: Value) -> Value {
+    let request: GalleryVisibility = match serde_json::from_value(json) {
+        Ok(request) => request,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Some(visibility)) = (
+        request.get_id().and_then(authenticate_with_id),
+        request.get_gallery_name(),
+        Visibility::from_name(&request.get_visibility()),
+    ) {
+        return json!({"success": set_visibility(&user_row, &gallery_name, visibility)});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_gallery_update(json: Value) -> Value {
+    let update: GalleryUpdate = match serde_json::from_value(json) {
+        Ok(update) => update,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Ok(description), Ok(cover_image)) = (
+        update.get_id().and_then(authenticate_with_id),
+        update.get_gallery_name(),
+        update.get_description(),
+        update.get_cover_image(),
+    ) {
+        return json!({
+            "success": update_gallery(&user_row, &gallery_name, description, cover_image)
+        });
+    }
+    json!({"success": false})
+}
+
+pub fn handle_gallery_reorder(json: Value) -> Value {
+    let reorder: GalleryReorder = match serde_json::from_value(json) {
+        Ok(reorder) => reorder,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_names)) = (
+        reorder.get_id().and_then(authenticate_with_id),
+        reorder.get_gallery_names(),
+    ) {
+        return json!({"success": reorder_galleries(&user_row, &gallery_names)});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_gallery_move(json: Value) -> Value {
+    let gallery_move: GalleryMove = match serde_json::from_value(json) {
+        Ok(gallery_move) => gallery_move,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Ok(parent)) = (
+        gallery_move.get_id().and_then(authenticate_with_id),
+        gallery_move.get_gallery_name(),
+        gallery_move.get_parent(),
+    ) {
+        return json!({"success": move_gallery(&user_row, &gallery_name, parent.as_deref())});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_gallery_deletion(json: Value) -> Value {
+    let delete: GalleryDelete = match serde_json::from_value(json) {
+        Ok(delete) => delete,
+        Err(_) => return json!({"success": false}),
+    };
+    if let Some(user_row) = delete.get_id().and_then(authenticate_with_id) {
+        return match delete.get_gallery_name() {
+            Some(gallery_name) => json!({"success": delete_gallery(&user_row, &gallery_name)}),
+            None => json!({"success": false, "message": "confirm_name must match gallery_name"}),
+        };
+    }
+    json!({"success": false})
+}
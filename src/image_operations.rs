@@ -0,0 +1,473 @@
+use crate::union_structs::{ImageDelete, ImageDescribe, ImageListing, ImageMove, ImageRename};
+use crate::gallery_members::{self, Role};
+use crate::pagination::Keyset;
+use crate::events::{self, EventKind, ImageEvent};
+use crate::{
+    authenticate_with_id, gallery_operations, image_processing, mysql_init, static_interface,
+};
+use mysql::params;
+use mysql::prelude::*;
+use serde_json::{json, Value};
+
+/// An image the user may edit, either in their own gallery or in one where they are an editor,
+/// together with the owner's id and username and the gallery's path.
+pub struct OwnedImage {
+    pub id: i32,
+    pub name: String,
+    pub gallery_id: i32,
+    pub gallery_name: String,
+    pub owner_id: i32,
+    pub username: String,
+}
+
+pub fn find_image_by_id(user_row: &mysql::Row, imageid: i32) -> Option<OwnedImage> {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let image_row: mysql::Row = mysql_init::get_conn()
+        .exec_first(
+            "SELECT images.id, images.name, galleries.id AS galleryid, users.id AS ownerid, users.username FROM images JOIN galleries ON images.gallery=galleries.id JOIN users ON galleries.user=users.id WHERE images.id=:imageid AND images.deleted IS NULL AND galleries.deleted IS NULL",
+            params!("imageid"=>imageid),
+        )
+        .expect("Failed to find image by id")?;
+    let gallery_id: i32 = mysql::from_value(image_row["galleryid"].clone());
+    let owner_id: i32 = mysql::from_value(image_row["ownerid"].clone());
+    if owner_id != userid && gallery_members::member_role(userid, gallery_id) != Some(Role::Editor) {
+        return None;
+    }
+    Some(OwnedImage {
+        id: mysql::from_value(image_row["id"].clone()),
+        name: mysql::from_value(image_row["name"].clone()),
+        gallery_id,
+        gallery_name: gallery_operations::gallery_path(gallery_id),
+        owner_id,
+        username: mysql::from_value(image_row["username"].clone()),
+    })
+}
+
+pub fn name_taken(galleryid: i32, image_name: &str) -> bool {
+    let existing: Option<i32> = mysql_init::get_conn()
+        .exec_first(
+            "SELECT id FROM images WHERE gallery=:galleryid AND name=:imagename AND deleted IS NULL",
+            params!("galleryid"=>galleryid, "imagename"=>image_name),
+        )
+        .expect("Failed to check for duplicate image");
+    existing.is_some()
+}
+
+/// Moves an image to the trash. Its row and label mappings are kept until it is purged.
+pub fn delete_image(user_row: &mysql::Row, imageid: i32) -> bool {
+    if let Some(image) = find_image_by_id(user_row, imageid) {
+        mysql_init::get_conn()
+            .exec_drop(
+                "UPDATE images SET deleted=NOW() WHERE id=:imageid",
+                params!("imageid"=>image.id),
+            )
+            .expect("Failed to delete image");
+        gallery_operations::touch_gallery(image.gallery_id);
+        static_interface::trash_image(&image.username, &image.gallery_name, &image.name, image.id);
+        image_processing::clear_cached(&image.username, &image.gallery_name, &image.name);
+        events::publish(ImageEvent::new(EventKind::Deleted, &image));
+        return true;
+    }
+    false
+}
+
+/// Renames an image within its gallery, failing if the new name is already used there.
+pub fn rename_image(user_row: &mysql::Row, imageid: i32, new_name: &str) -> bool {
+    if let Some(image) = find_image_by_id(user_row, imageid) {
+        if image.name == new_name {
+            return true;
+        }
+        if name_taken(image.gallery_id, new_name) {
+            return false;
+        }
+        if static_interface::move_image(&image.username, &image.gallery_name, &image.name, &image.gallery_name, new_name)
+            .is_err()
+        {
+            return false;
+        }
+        let renamed = mysql_init::get_conn().exec_drop(
+            "UPDATE images SET name=:imagename WHERE id=:imageid",
+            params!("imagename"=>new_name, "imageid"=>image.id),
+        );
+        if renamed.is_err() {
+            static_interface::move_image(&image.username, &image.gallery_name, new_name, &image.gallery_name, &image.name)
+                .expect("Failed to move image file back");
+            return false;
+        }
+        gallery_operations::touch_gallery(image.gallery_id);
+        image_processing::clear_cached(&image.username, &image.gallery_name, &image.name);
+        let mut renamed = ImageEvent::new(EventKind::Renamed, &image);
+        renamed.image_name = String::from(new_name);
+        renamed.previous_name = Some(image.name);
+        events::publish(renamed);
+        return true;
+    }
+    false
+}
+
+/// The gallery the user can move the image into under the given name: the one it is already in,
+/// or another of the owner's galleries that has no image with that name.
+pub fn move_target(user_row: &mysql::Row, image: &OwnedImage, gallery_name: &str, image_name: &str) -> Option<i32> {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if image.owner_id != userid {
+        return None;
+    }
+    let target_gallery = gallery_operations::find_gallery_id(userid, gallery_name)?;
+    if target_gallery != image.gallery_id && name_taken(target_gallery, image_name) {
+        return None;
+    }
+    Some(target_gallery)
+}
+
+/// Moves an image into another of the user's galleries, keeping its name. Only the owner can move
+/// images, since editors of a shared gallery can't reach the owner's other galleries.
+pub fn move_image(user_row: &mysql::Row, imageid: i32, gallery_name: &str) -> bool {
+    if let Some(image) = find_image_by_id(user_row, imageid) {
+        let target_gallery = match move_target(user_row, &image, gallery_name, &image.name) {
+            Some(target_gallery) => target_gallery,
+            None => return false,
+        };
+        if image.gallery_id == target_gallery {
+            return true;
+        }
+        if static_interface::move_image(&image.username, &image.gallery_name, &image.name, gallery_name, &image.name)
+            .is_err()
+        {
+            return false;
+        }
+        let moved = mysql_init::get_conn().exec_drop(
+            "UPDATE images SET gallery=:galleryid WHERE id=:imageid",
+            params!("galleryid"=>target_gallery, "imageid"=>image.id),
+        );
+        if moved.is_err() {
+            static_interface::move_image(&image.username, gallery_name, &image.name, &image.gallery_name, &image.name)
+                .expect("Failed to move image file back");
+            return false;
+        }
+        gallery_operations::touch_gallery(image.gallery_id);
+        gallery_operations::touch_gallery(target_gallery);
+        image_processing::clear_cached(&image.username, &image.gallery_name, &image.name);
+        events::publish(ImageEvent::new(EventKind::Deleted, &image));
+        let mut added = ImageEvent::new(EventKind::Added, &image);
+        added.gallery_id = target_gallery;
+        added.gallery_name = String::from(gallery_name);
+        events::publish(added);
+        return true;
+    }
+    false
+}
+
+/// Whether images in the user's galleries must have alt text, from their accessibility_strict
+/// setting.
+pub fn requires_alt_text(ownerid: i32) -> bool {
+    let strict: Option<bool> = mysql_init::get_conn()
+        .exec_first(
+            "SELECT accessibility_strict FROM users WHERE id=:userid",
+            params!("userid"=>ownerid),
+        )
+        .expect("Failed to query accessibility setting");
+    strict.unwrap_or(false)
+}
+
+/// Text that is empty or only whitespace counts as missing.
+pub fn non_empty(text: Option<String>) -> Option<String> {
+    text.filter(|text| !text.trim().is_empty())
+}
+
+/// Whether the image would still have alt text after setting it to `alt_text`, or leaving it as is
+/// for None, if its owner requires alt text.
+pub fn keeps_alt_text(image: &OwnedImage, alt_text: Option<&str>) -> bool {
+    if !requires_alt_text(image.owner_id) {
+        return true;
+    }
+    match alt_text {
+        Some(alt_text) => !alt_text.trim().is_empty(),
+        None => {
+            let current: Option<Option<String>> = mysql_init::get_conn()
+                .exec_first(
+                    "SELECT alt_text FROM images WHERE id=:imageid",
+                    params!("imageid"=>image.id),
+                )
+                .expect("Failed to query image alt text");
+            non_empty(current.flatten()).is_some()
+        }
+    }
+}
+
+/// Changes an image's title, caption and alt text. None leaves a field unchanged and an empty
+/// string clears it, except that alt text can't be cleared if the gallery owner requires it.
+pub fn describe_image(
+    user_row: &mysql::Row,
+    imageid: i32,
+    title: Option<String>,
+    caption: Option<String>,
+    alt_text: Option<String>,
+) -> bool {
+    if let Some(image) = find_image_by_id(user_row, imageid) {
+        let mut conn = mysql_init::get_conn();
+        let (current_title, current_caption, current_alt_text): (
+            Option<String>,
+            Option<String>,
+            Option<String>,
+        ) = conn
+            .exec_first(
+                "SELECT title, caption, alt_text FROM images WHERE id=:imageid",
+                params!("imageid"=>image.id),
+            )
+            .expect("Failed to query image description")
+            .unwrap_or_default();
+        let title = title.map_or(current_title, |title| non_empty(Some(title)));
+        let caption = caption.map_or(current_caption, |caption| non_empty(Some(caption)));
+        let alt_text = alt_text.map_or(current_alt_text, |alt_text| non_empty(Some(alt_text)));
+        if alt_text.is_none() && requires_alt_text(image.owner_id) {
+            return false;
+        }
+        conn.exec_drop(
+            "UPDATE images SET title=:title, caption=:caption, alt_text=:alttext WHERE id=:imageid",
+            params!("title"=>title, "caption"=>caption, "alttext"=>alt_text, "imageid"=>image.id),
+        )
+        .expect("Failed to describe image");
+        return true;
+    }
+    false
+}
+
+/// How a gallery's images are sorted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageSort {
+    Uploaded,
+    Taken,
+    Name,
+    Size,
+}
+
+impl ImageSort {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "uploaded" => Some(ImageSort::Uploaded),
+            "taken" => Some(ImageSort::Taken),
+            "name" => Some(ImageSort::Name),
+            "size" => Some(ImageSort::Size),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            ImageSort::Uploaded => "uploaded",
+            ImageSort::Taken => "taken",
+            ImageSort::Name => "name",
+            ImageSort::Size => "size",
+        }
+    }
+    /// Images without a capture date are sorted by their upload time instead.
+    pub fn keyset(&self, descending: bool) -> Keyset {
+        match self {
+            ImageSort::Uploaded => Keyset::new(&["uploaded", "id"], descending),
+            ImageSort::Taken => Keyset::new(&["COALESCE(taken, uploaded)", "id"], descending),
+            ImageSort::Name => Keyset::new(&["name", "id"], descending),
+            ImageSort::Size => Keyset::new(&["size", "id"], descending),
+        }
+    }
+}
+
+/// The file extensions of an image format.
+fn format_extensions(format: &str) -> Option<&'static [&'static str]> {
+    match format {
+        "jpeg" | "jpg" => Some(&["jpg", "jpeg"]),
+        "png" => Some(&["png"]),
+        _ => None,
+    }
+}
+
+/// Which of a gallery's images to list and in what order. The date range applies to the capture
+/// date, or the upload time for images without one.
+pub struct ImageFilter {
+    pub sort: ImageSort,
+    pub descending: bool,
+    format: Option<String>,
+    label: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl ImageFilter {
+    /// Fails if any of the listing's parameters is invalid.
+    pub fn from_listing(listing: &ImageListing) -> Option<Self> {
+        let format = match listing.get_format() {
+            Some(format) => Some(format_extensions(&format).map(|_| format)?),
+            None => None,
+        };
+        Some(ImageFilter {
+            sort: match listing.get_sort() {
+                Some(sort) => ImageSort::from_name(&sort)?,
+                None => ImageSort::Uploaded,
+            },
+            descending: listing.get_descending().ok()?,
+            format,
+            label: listing.get_label().ok()?,
+            from: listing.get_from().ok()?,
+            to: listing.get_to().ok()?,
+        })
+    }
+    /// Extra conditions on the images table, each starting with ` AND`, and their parameters.
+    pub fn conditions(&self) -> (String, Vec<(String, mysql::Value)>) {
+        let mut conditions = String::new();
+        let mut params: Vec<(String, mysql::Value)> = vec![];
+        if let Some(extensions) = self.format.as_deref().and_then(format_extensions) {
+            let matches: Vec<String> = extensions
+                .iter()
+                .enumerate()
+                .map(|(i, extension)| {
+                    params.push((format!("extension{}", i), mysql::Value::from(format!("%.{}", extension))));
+                    format!("name LIKE :extension{}", i)
+                })
+                .collect();
+            conditions.push_str(&format!(" AND ({})", matches.join(" OR ")));
+        }
+        if let Some(label) = &self.label {
+            conditions.push_str(" AND id IN (SELECT labelmap.imageid FROM labelmap JOIN labels ON labelmap.labelid=labels.id WHERE labels.name=:label)");
+            params.push((String::from("label"), mysql::Value::from(label)));
+        }
+        if let Some(from) = &self.from {
+            conditions.push_str(" AND COALESCE(taken, uploaded) >= :from");
+            params.push((String::from("from"), mysql::Value::from(from)));
+        }
+        if let Some(to) = &self.to {
+            conditions.push_str(" AND COALESCE(taken, uploaded) <= :to");
+            params.push((String::from("to"), mysql::Value::from(to)));
+        }
+        (conditions, params)
+    }
+    /// The query parameters that reproduce this filter, for links to other pages of the listing.
+    pub fn link_params(&self) -> Vec<(&'static str, String)> {
+        let mut params = vec![];
+        if self.sort != ImageSort::Uploaded {
+            params.push(("sort", String::from(self.sort.name())));
+        }
+        if self.descending {
+            params.push(("order", String::from("desc")));
+        }
+        let optional = [("format", &self.format), ("label", &self.label), ("from", &self.from), ("to", &self.to)];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                params.push((name, value.clone()));
+            }
+        }
+        params
+    }
+}
+
+fn results(results: Vec<(i32, bool)>) -> Value {
+    let images: Vec<Value> = results
+        .into_iter()
+        .map(|(image_id, success)| json!({"image_id": image_id, "success": success}))
+        .collect();
+    json!({"success": true, "images": images})
+}
+
+pub fn handle_image_deletion(json: Value) -> Value {
+    let delete: ImageDelete = match serde_json::from_value(json) {
+        Ok(delete) => delete,
+        Err(_) => return json!({"success": false}),
+    };
+    if let Some(user_row) = delete.get_id().and_then(authenticate_with_id) {
+        return results(
+            delete
+                .get_image_ids()
+                .into_iter()
+                .map(|imageid| (imageid, delete_image(&user_row, imageid)))
+                .collect(),
+        );
+    }
+    json!({"success": false})
+}
+
+pub fn handle_image_rename(json: Value) -> Value {
+    let rename: ImageRename = match serde_json::from_value(json) {
+        Ok(rename) => rename,
+        Err(_) => return json!({"success": false}),
+    };
+    if let Some(user_row) = rename.get_id().and_then(authenticate_with_id) {
+        return results(
+            rename
+                .get_renames()
+                .into_iter()
+                .map(|(imageid, new_name)| match new_name {
+                    Some(new_name) => (imageid, rename_image(&user_row, imageid, &new_name)),
+                    None => (imageid, false),
+                })
+                .collect(),
+        );
+    }
+    json!({"success": false})
+}
+
+pub fn handle_image_description(json: Value) -> Value {
+    let describe: ImageDescribe = match serde_json::from_value(json) {
+        Ok(describe) => describe,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Ok(title), Ok(caption), Ok(alt_text)) = (
+        describe.get_id().and_then(authenticate_with_id),
+        describe.get_title(),
+        describe.get_caption(),
+        describe.get_alt_text(),
+    ) {
+        return json!({
+            "success": describe_image(&user_row, describe.get_image_id(), title, caption, alt_text)
+        });
+    }
+    json!({"success": false})
+}
+
+pub fn handle_image_move(json: Value) -> Value {
+    let image_move: ImageMove = match serde_json::from_value(json) {
+        Ok(image_move) => image_move,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name)) = (
+        image_move.get_id().and_then(authenticate_with_id),
+        image_move.get_gallery_name(),
+    ) {
+        return results(
+            image_move
+                .get_image_ids()
+                .into_iter()
+                .map(|imageid| (imageid, move_image(&user_row, imageid, &gallery_name)))
+                .collect(),
+        );
+    }
+    json!({"success": false})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_filters() {
+        let listing: ImageListing = serde_json::from_value(
+            json!({"sort": "taken", "order": "desc", "format": "jpg", "to": "2022-06-30"}),
+        )
+        .unwrap();
+        let filter = ImageFilter::from_listing(&listing).unwrap();
+        assert_eq!(filter.sort.keyset(filter.descending).order_clause(), "COALESCE(taken, uploaded) DESC, id DESC");
+        let (conditions, params) = filter.conditions();
+        assert_eq!(
+            conditions,
+            " AND (name LIKE :extension0 OR name LIKE :extension1) AND COALESCE(taken, uploaded) <= :to"
+        );
+        assert_eq!(params.len(), 3);
+        assert_eq!(
+            filter.link_params(),
+            vec![
+                ("sort", String::from("taken")),
+                ("order", String::from("desc")),
+                ("format", String::from("jpg")),
+                ("to", String::from("2022-06-30 23:59:59")),
+            ]
+        );
+        let unknown: ImageListing = serde_json::from_value(json!({"format": "gif"})).unwrap();
+        assert!(ImageFilter::from_listing(&unknown).is_none());
+    }
+}
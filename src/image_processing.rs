@@ -0,0 +1,364 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageOutputFormat};
+use crate::metadata;
+use crate::transform_cache;
+use lazy_static::lazy_static;
+
+const CACHE_ROOT: &str = "/var/static/cache";
+const ALLOWED_DIMENSIONS: [u32; 12] = [64, 128, 256, 320, 480, 640, 800, 1024, 1280, 1600, 1920, 2048];
+const ALLOWED_QUALITIES: [u8; 6] = [50, 60, 70, 80, 90, 100];
+const DEFAULT_QUALITY: u8 = 80;
+
+lazy_static! {
+    static ref THUMB_SIZE: u32 = env_or("UNION_THUMB_SIZE", 256);
+    static ref MEDIUM_SIZE: u32 = env_or("UNION_MEDIUM_SIZE", 1024);
+    static ref LARGE_SIZE: u32 = env_or("UNION_LARGE_SIZE", 2048);
+    static ref THUMB_QUALITY: u8 = env_or("UNION_THUMB_QUALITY", 70);
+    static ref MEDIUM_QUALITY: u8 = env_or("UNION_MEDIUM_QUALITY", 80);
+    static ref LARGE_QUALITY: u8 = env_or("UNION_LARGE_QUALITY", 85);
+    static ref ORIGINAL_QUALITY: u8 = env_or("UNION_ORIGINAL_QUALITY", 92);
+    static ref ORIENT_RENDITIONS: bool = env_or("UNION_ORIENT_RENDITIONS", true);
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rendition {
+    Thumb,
+    Medium,
+    Large,
+}
+
+impl Rendition {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "thumb" => Some(Rendition::Thumb),
+            "medium" => Some(Rendition::Medium),
+            "large" => Some(Rendition::Large),
+            _ => None,
+        }
+    }
+    /// Whether the rendition is small enough to show on shares that don't allow downloads.
+    pub fn is_preview(&self) -> bool {
+        matches!(self, Rendition::Thumb | Rendition::Medium)
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rendition::Thumb => "thumb",
+            Rendition::Medium => "medium",
+            Rendition::Large => "large",
+        }
+    }
+    fn max_dimension(&self) -> u32 {
+        match self {
+            Rendition::Thumb => *THUMB_SIZE,
+            Rendition::Medium => *MEDIUM_SIZE,
+            Rendition::Large => *LARGE_SIZE,
+        }
+    }
+    fn quality(&self) -> u8 {
+        match self {
+            Rendition::Thumb => *THUMB_QUALITY,
+            Rendition::Medium => *MEDIUM_QUALITY,
+            Rendition::Large => *LARGE_QUALITY,
+        }
+    }
+}
+
+fn original_path(username: &str, gallery: &str, image_title: &str) -> String {
+    format!("/var/static/root/u/{}/{}/{}", username, gallery, image_title)
+}
+
+fn rendition_path(rendition: Rendition, username: &str, gallery: &str, image_title: &str) -> String {
+    format!(
+        "{}/{}/{}/{}/{}",
+        CACHE_ROOT,
+        rendition.name(),
+        username,
+        gallery,
+        image_title
+    )
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    JpegEncoder::new_with_quality(&mut bytes, quality)
+        .encode_image(image)
+        .ok()?;
+    Some(bytes)
+}
+
+/// Rotates and flips decoded pixels so that they match the given EXIF orientation.
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Decodes an image for resizing, turning it upright unless disabled with
+/// `UNION_ORIENT_RENDITIONS`.
+fn load_for_rendering(original: &[u8]) -> Option<DynamicImage> {
+    let image = image::load_from_memory(original).ok()?;
+    if *ORIENT_RENDITIONS {
+        Some(apply_orientation(image, metadata::orientation(original)))
+    } else {
+        Some(image)
+    }
+}
+
+/// Physically turns a JPEG upright according to its EXIF orientation and then rotates it
+/// clockwise by `degrees` (0, 90, 180 or 270). The EXIF block is kept with its orientation reset.
+pub fn rotate_jpeg(jpeg: &[u8], degrees: u32) -> Option<Vec<u8>> {
+    let image = apply_orientation(image::load_from_memory(jpeg).ok()?, metadata::orientation(jpeg));
+    let rotated = match degrees {
+        0 => image,
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => return None,
+    };
+    metadata::transplant_exif(jpeg, &encode_jpeg(&rotated, *ORIGINAL_QUALITY)?)
+}
+
+/// Drops every cached rendition and transformation of an image so they are regenerated from the
+/// original.
+pub fn clear_cached(username: &str, gallery: &str, image_title: &str) {
+    for rendition in [Rendition::Thumb, Rendition::Medium, Rendition::Large].iter() {
+        let _ = std::fs::remove_file(rendition_path(*rendition, username, gallery, image_title));
+    }
+    transform_cache::remove_dir(&format!("{}/{}/{}/", username, gallery, image_title));
+}
+
+/// Drops every cached rendition and transformation of the images in a gallery.
+pub fn clear_gallery_cache(username: &str, gallery: &str) {
+    for rendition in [Rendition::Thumb, Rendition::Medium, Rendition::Large].iter() {
+        let _ = std::fs::remove_dir_all(format!("{}/{}/{}/{}", CACHE_ROOT, rendition.name(), username, gallery));
+    }
+    transform_cache::remove_dir(&format!("{}/{}/", username, gallery));
+}
+
+fn render(original: &[u8], rendition: Rendition) -> Option<Vec<u8>> {
+    let image = load_for_rendering(original)?;
+    let max_dimension = rendition.max_dimension();
+    let resized = if image.width() > max_dimension || image.height() > max_dimension {
+        image.resize(max_dimension, max_dimension, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    encode_jpeg(&resized, rendition.quality())
+}
+
+/// Returns the requested rendition of a stored image, generating and caching it on first use.
+pub fn get_rendition(
+    rendition: Rendition,
+    username: &str,
+    gallery: &str,
+    image_title: &str,
+) -> Option<Vec<u8>> {
+    let cached_path = rendition_path(rendition, username, gallery, image_title);
+    if let Ok(cached) = std::fs::read(&cached_path) {
+        return Some(cached);
+    }
+    let original = std::fs::read(original_path(username, gallery, image_title)).ok()?;
+    let rendered = render(&original, rendition)?;
+    let cached_dir = format!("{}/{}/{}/{}", CACHE_ROOT, rendition.name(), username, gallery);
+    if std::fs::create_dir_all(&cached_dir).is_ok() {
+        if let Err(e) = std::fs::write(&cached_path, &rendered) {
+            println!("Failed to cache rendition {}: {:?}", cached_path, e);
+        }
+    }
+    Some(rendered)
+}
+
+/// Generates and caches every rendition of a newly stored image, so that its first viewers don't
+/// wait for them. Returns whether they could all be generated.
+pub fn prepare_renditions(username: &str, gallery: &str, image_title: &str) -> bool {
+    [Rendition::Thumb, Rendition::Medium, Rendition::Large]
+        .iter()
+        .all(|rendition| get_rendition(*rendition, username, gallery, image_title).is_some())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    Contain,
+    Cover,
+    Fill,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Jpeg,
+    Png,
+}
+
+/// An arbitrary resize, crop and format conversion requested through the image URL.
+#[derive(Debug, PartialEq)]
+pub struct Transform {
+    width: Option<u32>,
+    height: Option<u32>,
+    fit: Fit,
+    format: Format,
+    quality: u8,
+}
+
+impl Transform {
+    /// Validates the transformation parameters against the allow-lists. Returns `Ok(None)` when
+    /// no transformation was requested.
+    pub fn from_params(
+        width: Option<u32>,
+        height: Option<u32>,
+        fit: Option<&str>,
+        format: Option<&str>,
+        quality: Option<u8>,
+    ) -> Result<Option<Self>, ()> {
+        if width.is_none() && height.is_none() && fit.is_none() && format.is_none() && quality.is_none() {
+            return Ok(None);
+        }
+        let allowed = |dimension: Option<u32>| match dimension {
+            Some(dimension) => ALLOWED_DIMENSIONS.contains(&dimension),
+            None => true,
+        };
+        if !allowed(width) || !allowed(height) {
+            return Err(());
+        }
+        let fit = match fit.unwrap_or("contain") {
+            "contain" => Fit::Contain,
+            "cover" => Fit::Cover,
+            "fill" => Fit::Fill,
+            _ => return Err(()),
+        };
+        let format = match format.unwrap_or("jpeg") {
+            "jpeg" | "jpg" => Format::Jpeg,
+            "png" => Format::Png,
+            _ => return Err(()),
+        };
+        let quality = quality.unwrap_or(DEFAULT_QUALITY);
+        if !ALLOWED_QUALITIES.contains(&quality) {
+            return Err(());
+        }
+        Ok(Some(Transform {
+            width,
+            height,
+            fit,
+            format,
+            quality,
+        }))
+    }
+    pub fn content_type(&self) -> &'static str {
+        match self.format {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+        }
+    }
+    /// The cache key of this transformation applied to the given image.
+    pub fn cache_key(&self, username: &str, gallery: &str, image_title: &str) -> String {
+        let dimension = |dimension: Option<u32>| dimension.map(|d| d.to_string()).unwrap_or(String::from("auto"));
+        let (fit, extension) = (
+            match self.fit {
+                Fit::Contain => "contain",
+                Fit::Cover => "cover",
+                Fit::Fill => "fill",
+            },
+            match self.format {
+                Format::Jpeg => "jpg",
+                Format::Png => "png",
+            },
+        );
+        format!(
+            "{}/{}/{}/{}x{}_{}_q{}.{}",
+            username,
+            gallery,
+            image_title,
+            dimension(self.width),
+            dimension(self.height),
+            fit,
+            self.quality,
+            extension
+        )
+    }
+    fn apply(&self, original: &[u8]) -> Option<Vec<u8>> {
+        let image = load_for_rendering(original)?;
+        let width = self.width.unwrap_or(u32::MAX);
+        let height = self.height.unwrap_or(u32::MAX);
+        let transformed = match (self.width, self.height) {
+            (None, None) => image,
+            (Some(_), Some(_)) if self.fit == Fit::Cover => {
+                image.resize_to_fill(width, height, FilterType::Lanczos3)
+            }
+            (Some(_), Some(_)) if self.fit == Fit::Fill => {
+                image.resize_exact(width, height, FilterType::Lanczos3)
+            }
+            _ => image.resize(width, height, FilterType::Lanczos3),
+        };
+        match self.format {
+            Format::Jpeg => encode_jpeg(&transformed, self.quality),
+            Format::Png => {
+                let mut bytes = vec![];
+                transformed
+                    .write_to(&mut std::io::Cursor::new(&mut bytes), ImageOutputFormat::Png)
+                    .ok()?;
+                Some(bytes)
+            }
+        }
+    }
+}
+
+/// Applies a transformation to a stored image, going through the disk-backed LRU cache.
+pub fn get_transformed(
+    transform: &Transform,
+    username: &str,
+    gallery: &str,
+    image_title: &str,
+) -> Option<Vec<u8>> {
+    transform_cache::get_or_insert(&transform.cache_key(username, gallery, image_title), || {
+        transform.apply(&std::fs::read(original_path(username, gallery, image_title)).ok()?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_transform() {
+        assert_eq!(Transform::from_params(None, None, None, None, None), Ok(None));
+    }
+    #[test]
+    fn allowed_transforms() {
+        let transform = Transform::from_params(Some(256), Some(128), Some("cover"), Some("png"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(transform.content_type(), "image/png");
+        assert_eq!(
+            transform.cache_key("user", "gallery", "a.jpg"),
+            "user/gallery/a.jpg/256x128_cover_q80.png"
+        );
+    }
+    #[test]
+    fn disallowed_transforms() {
+        vec![
+            Transform::from_params(Some(257), None, None, None, None),
+            Transform::from_params(Some(256), Some(5000), None, None, None),
+            Transform::from_params(None, None, Some("stretch"), None, None),
+            Transform::from_params(None, None, None, Some("gif"), None),
+            Transform::from_params(None, None, None, None, Some(42)),
+        ]
+        .into_iter()
+        .for_each(|transform| {
+            assert!(transform.is_err());
+        });
+    }
+}
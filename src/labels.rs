@@ -0,0 +1,81 @@
+use crate::events::{self, EventKind, ImageEvent};
+use crate::image_operations::{self, OwnedImage};
+use crate::mysql_init;
+use mysql::params;
+use mysql::prelude::*;
+
+/// A user's labels, as (id, name) pairs in alphabetical order.
+pub fn list_labels(userid: i32) -> Vec<(i32, String)> {
+    mysql_init::get_conn()
+        .exec(
+            "SELECT id, name FROM labels WHERE user=:userid ORDER BY name",
+            params!("userid"=>userid),
+        )
+        .expect("Failed to list labels")
+}
+
+/// The names of the labels on an image.
+pub fn image_labels(imageid: i32) -> Vec<String> {
+    mysql_init::get_conn()
+        .exec(
+            "SELECT labels.name FROM labelmap JOIN labels ON labelmap.labelid=labels.id WHERE labelmap.imageid=:imageid ORDER BY labels.name",
+            params!("imageid"=>imageid),
+        )
+        .expect("Failed to list image labels")
+}
+
+/// Labels an image the user may edit. Labels belong to the image's owner and are created the
+/// first time they are used. Labelling an image twice with the same label does nothing.
+pub fn add_label(user_row: &mysql::Row, imageid: i32, label: &str) -> bool {
+    if let Some(image) = image_operations::find_image_by_id(user_row, imageid) {
+        let mut conn = mysql_init::get_conn();
+        let existing: Option<i32> = conn
+            .exec_first(
+                "SELECT id FROM labels WHERE user=:userid AND name=:label",
+                params!("userid"=>image.owner_id, "label"=>label),
+            )
+            .expect("Failed to find label");
+        let labelid = match existing {
+            Some(labelid) => labelid,
+            None => {
+                conn.exec_drop(
+                    "INSERT INTO labels(user, name) VALUES (:userid, :label)",
+                    params!("userid"=>image.owner_id, "label"=>label),
+                )
+                .expect("Failed to create label");
+                conn.last_insert_id() as i32
+            }
+        };
+        conn.exec_drop(
+            "INSERT INTO labelmap(labelid, imageid) SELECT :labelid, :imageid FROM DUAL WHERE NOT EXISTS (SELECT * FROM labelmap WHERE labelid=:labelid AND imageid=:imageid)",
+            params!("labelid"=>labelid, "imageid"=>image.id),
+        )
+        .expect("Failed to label image");
+        publish_labels(&image);
+        return true;
+    }
+    false
+}
+
+/// Takes a label off an image the user may edit.
+pub fn remove_label(user_row: &mysql::Row, imageid: i32, label: &str) -> bool {
+    if let Some(image) = image_operations::find_image_by_id(user_row, imageid) {
+        let mut conn = mysql_init::get_conn();
+        conn.exec_drop(
+            "DELETE labelmap FROM labelmap JOIN labels ON labelmap.labelid=labels.id WHERE labelmap.imageid=:imageid AND labels.user=:userid AND labels.name=:label",
+            params!("imageid"=>image.id, "userid"=>image.owner_id, "label"=>label),
+        )
+        .expect("Failed to remove image label");
+        if conn.affected_rows() > 0 {
+            publish_labels(&image);
+            return true;
+        }
+    }
+    false
+}
+
+fn publish_labels(image: &OwnedImage) {
+    let mut labelled = ImageEvent::new(EventKind::Labelled, image);
+    labelled.labels = Some(image_labels(image.id));
+    events::publish(labelled);
+}
@@ -3,6 +3,7 @@ use actix::{Actor, StreamHandler};
 use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_actors::ws;
 use futures_util::stream::StreamExt as _;
+use lazy_static::lazy_static;
 use mysql::params;
 use mysql::prelude::*;
 use rand::distributions::Alphanumeric;
@@ -18,16 +19,46 @@ use serde::Deserialize;
 use serde_json::{json, Value};
 use std::fs::File;
 use std::io::BufReader;
-use union_structs::{GalleryCreate, ImageCreate, Login, Signup};
+use std::time::{Duration, Instant};
+use union_structs::{
+    GalleryCreate, ImageCreate, ImageRotate, ImageStatus, Login, Scope, SettingsUpdate,
+    Signup,
+};
+use utoipa::IntoParams;
 
+mod api;
+mod api_tokens;
+mod dispatch;
+mod events;
+mod gallery_members;
+mod gallery_operations;
+mod image_operations;
+mod image_processing;
+mod labels;
+mod metadata;
 mod mysql_init;
+mod pagination;
+mod sharing;
 mod static_interface;
+mod templates;
+mod transform_cache;
+mod trash;
 mod union_structs;
 
 const HTTPPORT: i32 = 80;
 const HTTPSPORT: i32 = 443;
 const PUBCERT: &str = "/etc/letsencrypt/live/union.tk/fullchain.pem";
 const KEY: &str = "/etc/letsencrypt/live/union.tk/privkey.pem";
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+lazy_static! {
+    /// How many bytes of images each user may store.
+    static ref USER_QUOTA_BYTES: i64 = std::env::var("UNION_USER_QUOTA_BYTES")
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(10 * 1024 * 1024 * 1024);
+}
 
 #[derive(Deserialize)]
 struct Info {
@@ -35,9 +66,9 @@ struct Info {
 }
 
 #[derive(Deserialize)]
-struct GalleryInfo {
-    username: String,
-    gallery: String,
+struct PathInfo {
+    name: String,
+    path: String,
 }
 
 #[derive(Deserialize)]
@@ -46,176 +77,632 @@ struct ImageServeInfo {
     gallery: String,
     image: String,
 }
+
+#[derive(Deserialize)]
+struct ImageQuery {
+    size: Option<String>,
+    w: Option<u32>,
+    h: Option<u32>,
+    fit: Option<String>,
+    format: Option<String>,
+    quality: Option<u8>,
+    share: Option<String>,
+    /// The share password, from the `X-Share-Password` header or a posted form and never from the
+    /// query string, so that it stays out of logs, browser history and Referer headers.
+    #[serde(skip)]
+    password: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SharePassword {
+    password: String,
+}
+
+/// The share password sent in the `X-Share-Password` header.
+fn share_password(hr: &HttpRequest) -> Option<String> {
+    hr.headers()
+        .get("X-Share-Password")
+        .and_then(|password| password.to_str().ok())
+        .map(String::from)
+}
+
+/// Which page of a listing to show, as described in `pagination::PageRequest`.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PageQuery {
+    after: Option<i32>,
+    before: Option<i32>,
+    limit: Option<u32>,
+}
+
+impl PageQuery {
+    fn page_request(&self) -> pagination::PageRequest {
+        pagination::PageRequest::new(self.after, self.before, self.limit)
+    }
+}
+
+/// A websocket connection. Sockets opened at `/ws/{name}` have the operation `name` as their url,
+/// those opened at `/ws` are multiplexed. `heartbeat` is when the client was last heard from.
 struct MyWs {
-    url: String,
+    url: Option<String>,
+    connection: dispatch::Connection,
+    heartbeat: Instant,
+}
+
+impl MyWs {
+    /// A socket signed in with the session from the `id` cookie or the session id or API token
+    /// from an `Authorization: Bearer` header, if the request has a valid one.
+    fn new(url: Option<String>, req: &HttpRequest) -> Self {
+        MyWs {
+            url,
+            connection: dispatch::Connection::new(session_cookie(req).or_else(|| api_tokens::bearer(req))),
+            heartbeat: Instant::now(),
+        }
+    }
 }
 
 impl Actor for MyWs {
     type Context = ws::WebsocketContext<Self>;
+    /// Pings the client every `HEARTBEAT_INTERVAL` and drops it once it has been silent for
+    /// `CLIENT_TIMEOUT`.
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connection.attach(ctx.address().recipient());
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            if Instant::now().duration_since(act.heartbeat) > CLIENT_TIMEOUT {
+                ctx.stop();
+            } else {
+                ctx.ping(b"");
+            }
+        });
+    }
+    fn stopped(&mut self, _: &mut Self::Context) {
+        self.connection.close();
+    }
 }
 
-fn handle_signup(json: Value) -> Value {
-    let signup: Signup = serde_json::from_value(json).expect("Invalid Signup JSON");
-    if let (Some(email), Some(password), Some(username)) = (
+impl Handler<events::Event> for MyWs {
+    type Result = ();
+    fn handle(&mut self, event: events::Event, ctx: &mut Self::Context) {
+        if !self.connection.may_receive(&event) {
+            return;
+        }
+        let push = json!({"id": Value::Null, "op": "event", "payload": event});
+        ctx.text(serde_json::to_string(&push).expect("Failed to Stringify JSON"));
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Scrypt
+        .hash_password(
+            password.as_bytes(),
+            None,
+            Params::new(12, 8, 1).unwrap(),
+            &salt,
+        )
+        .unwrap()
+        .to_string()
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    let parsed_hash = PasswordHash::new(password_hash).unwrap();
+    Scrypt.verify_password(password.as_bytes(), &parsed_hash).is_ok()
+}
+
+fn random_id(length: usize) -> String {
+    let vec: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(length).collect();
+    String::from_utf8(vec).expect("RNG error")
+}
+
+/// Creates a user. Returns None if the input is invalid and `Some(false)` if the username or
+/// email address is already taken.
+fn create_user(signup: &Signup) -> Option<bool> {
+    let (email, password, username) = match (
         signup.get_email(),
         signup.get_password(),
         signup.get_username(),
     ) {
-        let salt = SaltString::generate(&mut OsRng);
-        let password_hash = Scrypt
-            .hash_password(
-                password.as_bytes(),
-                None,
-                Params::new(12, 8, 1).unwrap(),
-                &salt,
-            )
-            .unwrap()
-            .to_string();
-        mysql_init::get_conn()
-            .exec_drop(
-                "INSERT INTO users(email, password, username) VALUES (:email, :password, :username);",
-                params!("email"=>email, "password"=>password_hash, "username"=>&username),
-            )
-            .expect("Failed to execute signup mysql statement");
-        static_interface::make_user_dir(username);
-        json!({ "success": true })
-    } else {
-        json!({"success" : false})
+        (Some(email), Some(password), Some(username)) => (email, password, username),
+        _ => return None,
+    };
+    let taken: Option<i32> = mysql_init::get_conn()
+        .exec_first(
+            "SELECT id FROM users WHERE username=:username OR email=:email",
+            params!("username"=>&username, "email"=>&email),
+        )
+        .expect("Failed to check for existing users");
+    if taken.is_some() {
+        return Some(false);
     }
+    let password_hash = hash_password(&password);
+    mysql_init::get_conn()
+        .exec_drop(
+            "INSERT INTO users(email, password, username) VALUES (:email, :password, :username);",
+            params!("email"=>email, "password"=>password_hash, "username"=>&username),
+        )
+        .expect("Failed to execute signup mysql statement");
+    static_interface::make_user_dir(username);
+    Some(true)
 }
 
-fn handle_login(json: Value) -> Value {
-    let login: Login = serde_json::from_value(json).expect("Invalid Login JSON");
-    if let (Some(email), Some(password)) = (login.get_email(), login.get_password()) {
-        let selected_user_row: Vec<mysql::Row> = mysql_init::get_conn()
-            .exec(
-                "SELECT * FROM users WHERE email=:email;",
-                params!("email"=>email),
-            )
-            .expect("Failed to execute login mysql statement");
-        if selected_user_row.len() == 1 {
-            let password_hash: String = mysql::from_value(selected_user_row[0]["password"].clone());
-            let user_id: i32 = mysql::from_value(selected_user_row[0]["id"].clone());
-            let parsed_hash = PasswordHash::new(&password_hash).unwrap();
-            if let Ok(_) = Scrypt.verify_password(password.as_bytes(), &parsed_hash) {
-                let vec: Vec<u8> = thread_rng().sample_iter(&Alphanumeric).take(255).collect();
-                let id = String::from_utf8(vec).expect("RNG error");
-                mysql_init::get_conn()
-                    .exec_drop(
-                        "DELETE FROM activesessions WHERE user=:userid;",
-                        params!("userid" => &user_id),
-                    )
-                    .expect("Failed to delete old ids");
-                mysql_init::get_conn()
-                    .exec_drop(
-                        "INSERT INTO activesessions VALUES (:id, :userid);",
-                        params!("id"=>&id, "userid"=>user_id),
-                    )
-                    .expect("Failed to init id");
-                return json!({"success": true, "id": id});
-            }
-        }
+fn handle_signup(json: Value) -> Value {
+    match serde_json::from_value::<Signup>(json) {
+        Ok(signup) => json!({"success": create_user(&signup) == Some(true)}),
+        Err(_) => json!({"success": false}),
     }
-    json!({"success": false})
 }
 
-fn handle_gallery_creation(json: Value) -> Value {
-    let gallery_create: GalleryCreate =
-        serde_json::from_value(json).expect("Invalid Gallery Creation JSON");
-    if let (Some(gallery_name), Some(id)) =
-        (gallery_create.get_gallery_name(), gallery_create.get_id())
-    {
-        if let Some(user_row) = authenticate_with_id(id) {
-            let userid: i32 = mysql::from_value(user_row["id"].clone());
-            let username = mysql::from_value(user_row["username"].clone());
+/// Checks the user's email and password and starts a new session, ending any previous one.
+/// Returns the session id.
+fn start_session(login: &Login) -> Option<String> {
+    let (email, password) = (login.get_email()?, login.get_password()?);
+    let selected_user_row: Vec<mysql::Row> = mysql_init::get_conn()
+        .exec(
+            "SELECT * FROM users WHERE email=:email;",
+            params!("email"=>email),
+        )
+        .expect("Failed to execute login mysql statement");
+    if selected_user_row.len() == 1 {
+        let password_hash: String = mysql::from_value(selected_user_row[0]["password"].clone());
+        let user_id: i32 = mysql::from_value(selected_user_row[0]["id"].clone());
+        if verify_password(&password, &password_hash) {
+            let id = random_id(255);
+            mysql_init::get_conn()
+                .exec_drop(
+                    "DELETE FROM activesessions WHERE user=:userid;",
+                    params!("userid" => &user_id),
+                )
+                .expect("Failed to delete old ids");
             mysql_init::get_conn()
                 .exec_drop(
-                    "INSERT INTO galleries(user, name) VALUES (:user, :name);",
-                    params!("user"=> userid, "name"=>&gallery_name),
+                    "INSERT INTO activesessions VALUES (:id, :userid);",
+                    params!("id"=>&id, "userid"=>user_id),
                 )
-                .expect("Failed to create gallery");
-            static_interface::make_gallery_dir(username, gallery_name);
-            return json!({"success": true});
+                .expect("Failed to init id");
+            return Some(id);
         }
     }
-    json!({"success": false})
+    None
+}
+
+fn handle_login(json: Value) -> Value {
+    match serde_json::from_value::<Login>(json).ok().as_ref().and_then(start_session) {
+        Some(id) => json!({"success": true, "id": id}),
+        None => json!({"success": false}),
+    }
 }
 
-fn handle_single_image(user_row: mysql::Row, image: ImageCreate) -> Option<()> {
-    if let (Some(image_name), Some(image), Some(gallery_name)) = (
-        image.get_image_name(),
-        image.get_image(),
-        image.get_gallery_name(),
+/// Creates a gallery, nested in another if its path has more than one segment. Fails if the
+/// parent doesn't exist or the path is taken.
+fn create_gallery(user_row: &mysql::Row, gallery_create: &GalleryCreate) -> bool {
+    let visibility = match gallery_create.get_visibility() {
+        Some(name) => gallery_operations::Visibility::from_name(&name),
+        None => Some(gallery_operations::Visibility::Private),
+    };
+    if let (Some(gallery_name), Some(visibility), Ok(description)) = (
+        gallery_create.get_gallery_name(),
+        visibility,
+        gallery_create.get_description(),
     ) {
         let userid: i32 = mysql::from_value(user_row["id"].clone());
         let username = mysql::from_value(user_row["username"].clone());
-        let gallery: Vec<mysql::Row> = mysql_init::get_conn()
-            .exec(
-                "SELECT * FROM galleries WHERE user=:userid AND name=:galleryname",
-                params!("userid"=>userid, "galleryname"=> &gallery_name),
+        let (parent_path, name) = gallery_operations::split_gallery_path(&gallery_name);
+        let parent = match parent_path {
+            Some(parent_path) => match gallery_operations::find_gallery_id(userid, parent_path) {
+                Some(parent) => Some(parent),
+                None => return false,
+            },
+            None => None,
+        };
+        if gallery_operations::find_gallery_id(userid, &gallery_name).is_some() {
+            return false;
+        }
+        mysql_init::get_conn()
+            .exec_drop(
+                "INSERT INTO galleries(user, name, parent, visibility, description, position) SELECT :user, :name, :parent, :visibility, :description, COALESCE(MAX(position)+1, 0) FROM galleries WHERE user=:user AND parent <=> :parent;",
+                params!(
+                    "user"=> userid,
+                    "name"=>name,
+                    "parent"=>parent,
+                    "visibility"=>visibility.name(),
+                    "description"=>description.filter(|description| !description.is_empty()),
+                ),
             )
-            .expect("Failed to find gallery for image");
-        if gallery.len() == 1 {
-            let galleryid: i32 = mysql::from_value(gallery[0]["id"].clone());
-            mysql_init::get_conn()
-                .exec_drop(
-                    "INSERT INTO images(gallery, name) VALUES (:galleryid, :imagename)",
-                    params!("galleryid"=>galleryid, "imagename"=>&image_name),
-                )
-                .expect("Failed to insert image into database");
-            static_interface::make_image(username, gallery_name, image_name, image);
-            return Some(());
+            .expect("Failed to create gallery");
+        static_interface::make_gallery_dir(username, gallery_name);
+        return true;
+    }
+    false
+}
+
+fn handle_gallery_creation(json: Value) -> Value {
+    let gallery_create: GalleryCreate = match serde_json::from_value(json) {
+        Ok(gallery_create) => gallery_create,
+        Err(_) => return json!({"success": false}),
+    };
+    if let Some(user_row) = gallery_create.get_id().and_then(authenticate_with_id) {
+        return json!({"success": create_gallery(&user_row, &gallery_create)});
+    }
+    json!({"success": false})
+}
+
+fn user_strip_mode(user_row: &mysql::Row) -> metadata::StripMode {
+    let strip_metadata: String = mysql::from_value(user_row["strip_metadata"].clone());
+    metadata::StripMode::from_name(&strip_metadata).unwrap_or(metadata::StripMode::None)
+}
+
+fn handle_settings(json: Value) -> Value {
+    let settings: SettingsUpdate = match serde_json::from_value(json) {
+        Ok(settings) => settings,
+        Err(_) => return json!({"success": false}),
+    };
+    match settings.get_id().and_then(authenticate_with_id) {
+        Some(user_row) => update_settings(&user_row, &settings),
+        None => json!({"success": false}),
+    }
+}
+
+/// Applies the settings that were given and returns all of the user's settings.
+fn update_settings(user_row: &mysql::Row, settings: &SettingsUpdate) -> Value {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let mut strip_mode = user_strip_mode(user_row);
+    let mut keep_original: bool = mysql::from_value(user_row["keep_original_metadata"].clone());
+    let mut gallery_order = gallery_operations::user_gallery_order(user_row);
+    let mut accessibility_strict: bool = mysql::from_value(user_row["accessibility_strict"].clone());
+    if let Some(name) = settings.get_strip_metadata() {
+        match metadata::StripMode::from_name(&name) {
+            Some(mode) => strip_mode = mode,
+            None => return json!({"success": false, "message": "Unknown strip_metadata setting"}),
         }
     }
-    None
+    if let Some(keep) = settings.get_keep_original_metadata() {
+        keep_original = keep;
+    }
+    if let Some(name) = settings.get_gallery_order() {
+        match gallery_operations::GalleryOrder::from_name(&name) {
+            Some(order) => gallery_order = order,
+            None => return json!({"success": false, "message": "Unknown gallery_order setting"}),
+        }
+    }
+    if let Some(strict) = settings.get_accessibility_strict() {
+        accessibility_strict = strict;
+    }
+    mysql_init::get_conn()
+        .exec_drop(
+            "UPDATE users SET strip_metadata=:strip, keep_original_metadata=:keep, gallery_order=:order, accessibility_strict=:strict WHERE id=:userid",
+            params!(
+                "strip"=>strip_mode.name(),
+                "keep"=>keep_original,
+                "order"=>gallery_order.name(),
+                "strict"=>accessibility_strict,
+                "userid"=>userid,
+            ),
+        )
+        .expect("Failed to update user settings");
+    json!({
+        "success": true,
+        "strip_metadata": strip_mode.name(),
+        "keep_original_metadata": keep_original,
+        "gallery_order": gallery_order.name(),
+        "accessibility_strict": accessibility_strict,
+    })
+}
+
+/// Strips an uploaded image as the gallery owner's settings ask, whoever uploads it. Returns the
+/// image to store, its metadata and, if the owner keeps original metadata, the EXIF to keep
+/// privately.
+fn strip_upload(
+    owner_row: &mysql::Row,
+    bytes: &[u8],
+) -> Option<(Vec<u8>, metadata::ImageMetadata, Option<Vec<u8>>)> {
+    let strip_mode = user_strip_mode(owner_row);
+    let keep_original: bool = mysql::from_value(owner_row["keep_original_metadata"].clone());
+    let mut image_metadata = metadata::extract_metadata(bytes);
+    let (bytes, original_exif) = metadata::strip(bytes, strip_mode)?;
+    if !keep_original {
+        image_metadata = image_metadata.strip(strip_mode);
+    }
+    Some((bytes, image_metadata, original_exif.filter(|_| keep_original)))
+}
+
+/// Stores one uploaded image, calling `decoded` once its data has been decoded.
+fn store_image(
+    user_row: &mysql::Row,
+    image: ImageCreate,
+    decoded: &dyn Fn(),
+) -> Result<image_operations::OwnedImage, ImageStatus> {
+    let image_name = match image.get_image_name() {
+        Some(image_name) => image_name,
+        None => return Err(ImageStatus::InvalidName),
+    };
+    let gallery_name = match image.get_gallery_name() {
+        Some(gallery_name) => gallery_name,
+        None => return Err(ImageStatus::UnknownGallery),
+    };
+    let (title, caption, alt_text) = match (image.get_title(), image.get_caption(), image.get_alt_text()) {
+        (Ok(title), Ok(caption), Ok(alt_text)) => (
+            image_operations::non_empty(title),
+            image_operations::non_empty(caption),
+            image_operations::non_empty(alt_text),
+        ),
+        _ => return Err(ImageStatus::Malformed),
+    };
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let owner_row = match image.get_owner() {
+        Ok(Some(owner)) => match gallery_operations::find_user(&owner) {
+            Some(owner_row) => owner_row,
+            None => return Err(ImageStatus::UnknownGallery),
+        },
+        Ok(None) => user_row.clone(),
+        Err(()) => return Err(ImageStatus::UnknownGallery),
+    };
+    let ownerid: i32 = mysql::from_value(owner_row["id"].clone());
+    let username: String = mysql::from_value(owner_row["username"].clone());
+    let galleryid = match gallery_operations::find_gallery_id(ownerid, &gallery_name) {
+        Some(galleryid) => galleryid,
+        None => return Err(ImageStatus::UnknownGallery),
+    };
+    if ownerid != userid
+        && gallery_members::member_role(userid, galleryid)
+            .is_none_or(|role| role < gallery_members::Role::Contributor)
+    {
+        return Err(ImageStatus::UnknownGallery);
+    }
+    if alt_text.is_none() && image_operations::requires_alt_text(ownerid) {
+        return Err(ImageStatus::MissingAltText);
+    }
+    let existing_images: Vec<mysql::Row> = mysql_init::get_conn()
+        .exec(
+            "SELECT * FROM images WHERE gallery=:galleryid AND name=:imagename AND deleted IS NULL",
+            params!("galleryid"=>galleryid, "imagename"=>&image_name),
+        )
+        .expect("Failed to check for duplicate image");
+    if !existing_images.is_empty() {
+        return Err(ImageStatus::Duplicate);
+    }
+    let bytes = match image.get_image().and_then(|image| static_interface::decode_image(&image)) {
+        Some(bytes) => bytes,
+        None => return Err(ImageStatus::BadEncoding),
+    };
+    let bytes = if image.get_auto_orient() && metadata::orientation(&bytes) != 1 {
+        match image_processing::rotate_jpeg(&bytes, 0) {
+            Some(bytes) => bytes,
+            None => return Err(ImageStatus::BadEncoding),
+        }
+    } else {
+        bytes
+    };
+    decoded();
+    let used_bytes: Option<i64> = mysql_init::get_conn()
+        .exec_first(
+            "SELECT CAST(COALESCE(SUM(images.size), 0) AS SIGNED) FROM images JOIN galleries ON images.gallery=galleries.id WHERE galleries.user=:userid",
+            params!("userid"=>ownerid),
+        )
+        .expect("Failed to compute user storage");
+    if used_bytes.unwrap_or(0) + bytes.len() as i64 > *USER_QUOTA_BYTES {
+        return Err(ImageStatus::OverQuota);
+    }
+    let (bytes, metadata, original_exif) = match strip_upload(&owner_row, &bytes) {
+        Some(stripped) => stripped,
+        None => return Err(ImageStatus::BadEncoding),
+    };
+    let mut conn = mysql_init::get_conn();
+    conn.exec_drop(
+        "INSERT INTO images(gallery, name, size, width, height, taken, exif, title, caption, alt_text) VALUES (:galleryid, :imagename, :size, :width, :height, :taken, :exif, :title, :caption, :alttext)",
+        params!(
+            "galleryid"=>galleryid,
+            "imagename"=>&image_name,
+            "size"=>bytes.len(),
+            "width"=>metadata.width,
+            "height"=>metadata.height,
+            "taken"=>&metadata.taken,
+            "exif"=>serde_json::to_string(&metadata).expect("Failed to Stringify JSON"),
+            "title"=>title,
+            "caption"=>caption,
+            "alttext"=>alt_text,
+        ),
+    )
+    .expect("Failed to insert image into database");
+    let stored = image_operations::OwnedImage {
+        id: conn.last_insert_id() as i32,
+        name: image_name.clone(),
+        gallery_id: galleryid,
+        gallery_name: gallery_name.clone(),
+        owner_id: ownerid,
+        username: username.clone(),
+    };
+    if let Some(original_exif) = original_exif {
+        static_interface::make_private_metadata(&username, &gallery_name, &image_name, &original_exif);
+    }
+    gallery_operations::touch_gallery(galleryid);
+    static_interface::make_image(username, gallery_name, image_name, &bytes);
+    events::publish(events::ImageEvent::new(events::EventKind::Added, &stored));
+    Ok(stored)
+}
+
+fn handle_image_rotation(json: Value) -> Value {
+    let rotate: ImageRotate = match serde_json::from_value(json) {
+        Ok(rotate) => rotate,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(id), Some(gallery), Some(image), Some(degrees)) = (
+        rotate.get_id(),
+        rotate.get_gallery_name(),
+        rotate.get_image_name(),
+        rotate.get_degrees(),
+    ) {
+        if let Some(user_row) = authenticate_with_id(id) {
+            let info = ImageServeInfo {
+                name: mysql::from_value(user_row["username"].clone()),
+                gallery,
+                image,
+            };
+            if let Some((username, gallery, image_row)) = find_owned_image(&user_row, &info) {
+                let imageid: i32 = mysql::from_value(image_row["id"].clone());
+                let image_name: String = mysql::from_value(image_row["name"].clone());
+                if let Some(rotated) = static_interface::load_image(&username, &gallery, &image_name)
+                    .and_then(|original| image_processing::rotate_jpeg(&original, degrees))
+                {
+                    let dimensions = metadata::extract_metadata(&rotated);
+                    mysql_init::get_conn()
+                        .exec_drop(
+                            "UPDATE images SET size=:size, width=:width, height=:height WHERE id=:imageid",
+                            params!(
+                                "size"=>rotated.len(),
+                                "width"=>dimensions.width,
+                                "height"=>dimensions.height,
+                                "imageid"=>imageid,
+                            ),
+                        )
+                        .expect("Failed to update rotated image");
+                    static_interface::make_image(username.clone(), gallery.clone(), image_name.clone(), &rotated);
+                    image_processing::clear_cached(&username, &gallery, &image_name);
+                    return json!({"success": true});
+                }
+            }
+        }
+    }
+    json!({"success": false})
 }
 
+/// Accepts a JSON array of images and stores them in the background. The response carries the
+/// job id and the submitted names in upload order. Progress is pushed to websockets subscribed to
+/// the uploader's account as upload events with that job id, and can be polled from
+/// `/post/image/{job}`.
 async fn image_upload_handler(hr: HttpRequest, mut stream: web::Payload) -> impl Responder {
-    if let Some(user_row) = authenticate(hr).await {
-        let mut bytes = web::BytesMut::new();
-        while let Some(item) = stream.next().await {
-            bytes.extend_from_slice(&item.expect("Error parsing posted bytes"));
+    let user_row = match authenticate(hr, Scope::Upload).await {
+        Some(user_row) => user_row,
+        None => return HttpResponse::Unauthorized().json(json!({"success": false})),
+    };
+    let mut bytes = web::BytesMut::new();
+    while let Some(item) = stream.next().await {
+        bytes.extend_from_slice(&item.expect("Error parsing posted bytes"));
+    }
+    let images = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(Value::Array(images)) => images,
+        _ => return HttpResponse::BadRequest().json(json!({"success": false})),
+    };
+    let job = random_id(32);
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let image_names: Vec<Option<String>> = images
+        .iter()
+        .map(|image| image["image_name"].as_str().map(String::from))
+        .collect();
+    for (index, image_name) in image_names.iter().enumerate() {
+        let received = upload_event(events::UploadStage::Received, &job, userid, index, image_name);
+        events::publish(received);
+    }
+    let background_job = job.clone();
+    actix_rt::spawn(async move {
+        let upload = web::block(move || run_upload_job(&user_row, &background_job, images));
+        if let Err(e) = upload.await {
+            println!("Upload job failed: {:?}", e);
         }
-        let mut json: serde_json::Value =
-            serde_json::from_slice(&bytes).expect("Failed to parse JSON");
-        let images: Vec<ImageCreate> = json
-            .as_array_mut()
-            .expect("Invalid image creation JSON")
-            .into_iter()
-            .map(|value| {
-                serde_json::from_value::<ImageCreate>(value.clone())
-                    .expect("Failed to convert JSON to CreateImage struct")
-            })
-            .collect();
-        let _ = images
-            .into_iter()
-            .filter_map(|image| handle_single_image(user_row.clone(), image))
-            .collect::<Vec<_>>();
+    });
+    HttpResponse::Accepted().json(json!({"success": true, "job": job, "images": image_names}))
+}
+
+/// The upload events of one of the user's recent jobs so far, and whether it has finished.
+async fn upload_job_handler(hr: HttpRequest, job: web::Path<String>) -> impl Responder {
+    let user_row = match authenticate(hr, Scope::Read).await {
+        Some(user_row) => user_row,
+        None => return HttpResponse::Unauthorized().json(json!({"success": false})),
+    };
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    match events::upload_job_events(&job, userid) {
+        Some(upload_events) => {
+            let finished = upload_events.iter().any(|event| event.event == events::UploadStage::Finished);
+            HttpResponse::Ok().json(json!({
+                "success": true,
+                "job": *job,
+                "finished": finished,
+                "events": upload_events,
+            }))
+        }
+        None => HttpResponse::NotFound().json(json!({"success": false})),
     }
-    HttpResponse::Ok().body("")
+}
+
+fn upload_event(
+    stage: events::UploadStage,
+    job: &str,
+    userid: i32,
+    index: usize,
+    image_name: &Option<String>,
+) -> events::UploadEvent {
+    let mut event = events::UploadEvent::new(stage, job, userid);
+    event.index = Some(index);
+    event.image_name = image_name.clone();
+    event
+}
+
+/// Stores the images of an upload job one by one, then renders their thumb, medium and large
+/// renditions.
+fn run_upload_job(user_row: &mysql::Row, job: &str, images: Vec<Value>) {
+    use events::UploadStage;
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    for (index, image) in images.into_iter().enumerate() {
+        let image_name = image["image_name"].as_str().map(String::from);
+        let event = |stage| upload_event(stage, job, userid, index, &image_name);
+        let stored = match serde_json::from_value::<ImageCreate>(image) {
+            Ok(image) => store_image(user_row, image, &|| {
+                events::publish(event(UploadStage::Decoded))
+            }),
+            Err(_) => Err(ImageStatus::Malformed),
+        };
+        match stored {
+            Ok(stored) => {
+                let mut stored_event = event(UploadStage::Stored);
+                stored_event.image_id = Some(stored.id);
+                events::publish(stored_event);
+                let gallery_name = &stored.gallery_name;
+                if image_processing::prepare_renditions(&stored.username, gallery_name, &stored.name) {
+                    let mut ready = event(UploadStage::RenditionsReady);
+                    ready.image_id = Some(stored.id);
+                    events::publish(ready);
+                } else {
+                    let mut failed = event(UploadStage::Failed);
+                    failed.image_id = Some(stored.id);
+                    failed.status = Some(ImageStatus::RenditionsFailed);
+                    events::publish(failed);
+                }
+            }
+            Err(status) => {
+                let mut failed = event(UploadStage::Failed);
+                failed.status = Some(status);
+                events::publish(failed);
+            }
+        }
+    }
+    events::publish(events::UploadEvent::new(UploadStage::Finished, job, userid));
 }
 
 /// Handler for ws::Message message
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MyWs {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        if msg.is_ok() {
+            self.heartbeat = Instant::now();
+        }
         match msg {
+            Ok(ws::Message::Ping(bytes)) => ctx.pong(&bytes),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
             Ok(ws::Message::Text(text)) => {
-                let json: serde_json::Value = serde_json::from_str(&text).expect("No JSON format");
-                let returned_json = match self.url.as_str() {
-                    "login" => handle_login(json),
-                    "signup" => handle_signup(json),
-                    "creategallery" => handle_gallery_creation(json),
-                    _ => {
-                        serde_json::json!({
-                            "success": false,
-                            "message": "URL does not exist"
-                        })
-                    }
+                let returned_json = match &self.url {
+                    Some(url) => match serde_json::from_str(&text) {
+                        Ok(json) => dispatch::dispatch(url, self.connection.with_session(url, json))
+                            .unwrap_or_else(|| json!({"success": false, "message": "URL does not exist"})),
+                        Err(_) => json!({"success": false}),
+                    },
+                    None => self.connection.handle(&text),
                 };
                 ctx.text(serde_json::to_string(&returned_json).expect("Failed to Stringify JSON"))
             }
             Err(e) => {
                 println!("Websocket error: {:?}", e);
+                ctx.stop();
             }
             _ => (),
         }
@@ -227,16 +714,25 @@ async fn ws_response(
     req: HttpRequest,
     stream: web::Payload,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
-        MyWs {
-            url: info.name.clone(),
-        },
-        &req,
-        stream,
-    )
+    if !dispatch::same_origin(&req) {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    ws::start(MyWs::new(Some(info.name.clone()), &req), &req, stream)
+}
+
+async fn multiplexed_ws_response(req: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
+    if !dispatch::same_origin(&req) {
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    ws::start(MyWs::new(None, &req), &req, stream)
 }
 
+/// The user behind a session id or an API token. Tokens are only checked for being valid here;
+/// `dispatch` checks that they have the scope of the websocket operation they are used for.
 fn authenticate_with_id(id: String) -> Option<mysql::Row> {
+    if union_structs::API_TOKEN_REGEX.is_match(&id) {
+        return api_tokens::authenticate_token(&id).map(|(user_row, _)| user_row);
+    }
     let active_sessions: Vec<mysql::Row> = mysql_init::get_conn()
         .exec(
             "SELECT * FROM activesessions WHERE id=:userid;",
@@ -255,79 +751,392 @@ fn authenticate_with_id(id: String) -> Option<mysql::Row> {
     None
 }
 
-async fn authenticate(hr: HttpRequest) -> Option<mysql::Row> {
-    if let Some(cookie) = hr.cookie("id") {
-        if let Some(id) = union_structs::parse(&union_structs::ID_REGEX, cookie.value()) {
-            return authenticate_with_id(id);
+/// The session id from the `id` cookie.
+fn session_cookie(hr: &HttpRequest) -> Option<String> {
+    union_structs::parse(&union_structs::ID_REGEX, hr.cookie("id")?.value())
+}
+
+/// The user making the request, from the `id` cookie or from an `Authorization: Bearer` header
+/// with a session id or an API token that has the scope.
+async fn authenticate(hr: HttpRequest, scope: Scope) -> Option<mysql::Row> {
+    session_cookie(&hr)
+        .and_then(authenticate_with_id)
+        .or_else(|| api_tokens::authorize(&hr, scope))
+}
+
+/// One page of a user's top-level gallery rows, in the order they chose. Visitors only see public
+/// galleries.
+fn user_gallery_rows(
+    viewer: Option<&mysql::Row>,
+    owner: &mysql::Row,
+    request: pagination::PageRequest,
+) -> pagination::Page {
+    let userid: i32 = mysql::from_value(owner["id"].clone());
+    let visibility_filter = if gallery_operations::is_same_user(viewer, owner) {
+        ""
+    } else {
+        " AND visibility='public'"
+    };
+    pagination::fetch_page(
+        "galleries",
+        &format!("user=:userid AND parent IS NULL AND deleted IS NULL{}", visibility_filter),
+        vec![(String::from("userid"), mysql::Value::from(userid))],
+        gallery_operations::user_gallery_order(owner).keyset(),
+        request,
+    )
+}
+
+fn user_gallery_page(
+    viewer: Option<&mysql::Row>,
+    owner: &mysql::Row,
+    request: pagination::PageRequest,
+) -> (Vec<static_interface::GalleryDisplay>, pagination::Cursors) {
+    let username: String = mysql::from_value(owner["username"].clone());
+    let page = user_gallery_rows(viewer, owner, request);
+    let galleries = page
+        .rows
+        .into_iter()
+        .map(|user_gallery| {
+            let name: String = mysql::from_value(user_gallery["name"].clone());
+            static_interface::GalleryDisplay {
+                cover_url: gallery_operations::gallery_cover(&user_gallery)
+                    .map(|cover| format!("/u/{}/{}/{}?size=thumb", username, name, cover)),
+                description: mysql::from_value(user_gallery["description"].clone()),
+                name,
+            }
+        })
+        .collect();
+    (galleries, page.cursors)
+}
+
+async fn userpage_response(
+    info: web::Path<Info>,
+    page: web::Query<PageQuery>,
+    hr: HttpRequest,
+) -> impl Responder {
+    let viewer = authenticate(hr, Scope::Read).await;
+    if let Some(owner) = gallery_operations::find_user(&info.name) {
+        let username: String = mysql::from_value(owner["username"].clone());
+        let request = page.page_request();
+        let (galleries, cursors) = user_gallery_page(viewer.as_ref(), &owner, request);
+        let links = static_interface::PageLinks::new(
+            &format!("/u/{}", username),
+            cursors,
+            request.limit,
+            &[],
+        );
+        return match static_interface::get_user_page(&username, galleries, &links) {
+            Some(page) => HttpResponse::Ok().body(page),
+            None => HttpResponse::InternalServerError().body(""),
+        };
+    }
+    HttpResponse::Ok().body("")
+}
+
+/// The JSON version of a user page, for loading further pages of galleries.
+async fn user_listing_response(
+    info: web::Path<Info>,
+    page: web::Query<PageQuery>,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let viewer = authenticate(hr, Scope::Read).await;
+    if let Some(owner) = gallery_operations::find_user(&info.name) {
+        let username: String = mysql::from_value(owner["username"].clone());
+        let request = page.page_request();
+        let (galleries, cursors) = user_gallery_page(viewer.as_ref(), &owner, request);
+        let links = static_interface::PageLinks::new(
+            &format!("/list/u/{}", username),
+            cursors,
+            request.limit,
+            &[],
+        );
+        return HttpResponse::Ok().json(json!({
+            "success": true,
+            "galleries": static_interface::gallery_entries(&username, galleries),
+            "next": cursors.next,
+            "prev": cursors.prev,
+            "next_url": links.next,
+            "prev_url": links.prev,
+        }));
+    }
+    HttpResponse::NotFound().json(json!({"success": false}))
+}
+
+/// Looks up a gallery that the request may see, either through a share token or through the
+/// session cookie and the gallery's visibility. Returns the owner's row, the gallery's row and the
+/// share grant if one was used.
+async fn find_requested_gallery(
+    hr: &HttpRequest,
+    share: Option<&str>,
+    password: Option<&str>,
+    username: &str,
+    gallery: &str,
+) -> Option<(mysql::Row, mysql::Row, Option<sharing::ShareGrant>)> {
+    let gallery = union_structs::parse_gallery_path(gallery)?;
+    match share {
+        Some(token) => {
+            let grant = sharing::resolve_share(hr, token, password)?;
+            let owner_name: String = mysql::from_value(grant.owner["username"].clone());
+            let gallery_name =
+                gallery_operations::gallery_path(mysql::from_value(grant.gallery["id"].clone()));
+            if owner_name == username && gallery_name == gallery {
+                Some((grant.owner.clone(), grant.gallery.clone(), Some(grant)))
+            } else {
+                None
+            }
+        }
+        None => {
+            let viewer = authenticate(hr.clone(), Scope::Read).await;
+            let (owner, user_gallery) =
+                gallery_operations::find_viewable_gallery(viewer.as_ref(), username, &gallery)?;
+            Some((owner, user_gallery, None))
         }
     }
-    None
 }
 
-async fn userpage_response(info: web::Path<Info>, hr: HttpRequest) -> impl Responder {
-    if let Some(user_row) = authenticate(hr).await {
-        let username: String = mysql::from_value(user_row["username"].clone());
-        let userid: i32 = mysql::from_value(user_row["id"].clone());
-        if username == info.name {
-            let user_galleries: Vec<mysql::Row> = mysql_init::get_conn()
-                .exec(
-                    "SELECT * FROM galleries WHERE user=:userid;",
-                    params!("userid"=>userid),
-                )
-                .expect("Failed to get user galleries");
-            let gallery_names: Vec<String> = user_galleries
-                .into_iter()
-                .map(|user_gallery| mysql::from_value(user_gallery["name"].clone()))
-                .collect();
-            return HttpResponse::Ok()
-                .body(static_interface::get_user_page(&username, gallery_names).await);
+fn with_unlock_cookie(
+    mut response: actix_web::HttpResponseBuilder,
+    grant: &Option<sharing::ShareGrant>,
+) -> actix_web::HttpResponseBuilder {
+    if let Some(cookie) = grant.as_ref().and_then(|grant| grant.unlock_cookie()) {
+        response.cookie(cookie);
+    }
+    response
+}
+
+/// Serves everything below a user page. The path is a gallery path, optionally followed by an
+/// image name and then by `details`, as in `/u/someone/2022/summer/beach.jpg/details`.
+async fn path_response(
+    info: web::Path<PathInfo>,
+    query: web::Query<ImageQuery>,
+    page: web::Query<PageQuery>,
+    listing: web::Query<union_structs::ImageListing>,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let mut query = query.into_inner();
+    query.password = share_password(&hr);
+    serve_path(info, query, page, listing, hr).await
+}
+
+/// Like `path_response`, for the password form of a password-protected share.
+async fn path_password_response(
+    info: web::Path<PathInfo>,
+    query: web::Query<ImageQuery>,
+    page: web::Query<PageQuery>,
+    listing: web::Query<union_structs::ImageListing>,
+    form: web::Form<SharePassword>,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let mut query = query.into_inner();
+    query.password = Some(form.into_inner().password);
+    serve_path(info, query, page, listing, hr).await
+}
+
+async fn serve_path(
+    info: web::Path<PathInfo>,
+    query: ImageQuery,
+    page: web::Query<PageQuery>,
+    listing: web::Query<union_structs::ImageListing>,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let path = info.path.trim_end_matches('/');
+    let is_image = |name: &str| union_structs::parse(&union_structs::IMAGETITLE_REGEX, name).is_some();
+    let image_path = match gallery_operations::split_gallery_path(path) {
+        (Some(rest), last) if is_image(last) => Some((rest, last, false)),
+        (Some(rest), "details") => match gallery_operations::split_gallery_path(rest) {
+            (Some(gallery), image) if is_image(image) => Some((gallery, image, true)),
+            _ => None,
+        },
+        _ => None,
+    };
+    match image_path {
+        Some((gallery, image, details)) => {
+            let image_info = ImageServeInfo {
+                name: info.name.clone(),
+                gallery: String::from(gallery),
+                image: String::from(image),
+            };
+            if details {
+                image_details(image_info, hr).await
+            } else {
+                image_server(image_info, query, hr).await
+            }
         }
+        None => gallery_response(&info.name, path, &query, &page, &listing, hr).await,
     }
-    HttpResponse::Ok().body("")
 }
 
-async fn gallery_response(info: web::Path<GalleryInfo>, hr: HttpRequest) -> impl Responder {
-    if let Some(user_row) = authenticate(hr).await {
-        let userid: i32 = mysql::from_value(user_row["id"].clone());
-        let username: String = mysql::from_value(user_row["username"].clone());
-        if username == info.username {
-            if let Some(gallery) =
-                union_structs::parse(&union_structs::GALLERY_REGEX, &info.gallery)
-            {
-                let user_gallery: Vec<mysql::Row> = mysql_init::get_conn()
-                    .exec(
-                        "SELECT * FROM galleries WHERE user=:userid AND name=:galleryname",
-                        params!("userid"=>userid, "galleryname"=>&gallery),
-                    )
-                    .expect("Failed to query user gallery");
-                if user_gallery.len() == 1 {
-                    let gallery_id: i32 = mysql::from_value(user_gallery[0]["id"].clone());
-                    let gallery_name: String = mysql::from_value(user_gallery[0]["name"].clone());
-                    let user_images: Vec<mysql::Row> = mysql_init::get_conn()
-                        .exec(
-                            "SELECT * FROM images WHERE gallery=:gallery",
-                            params!("gallery"=>gallery_id),
-                        )
-                        .expect("Failed to select gallery images");
-                    let user_image_names: Vec<String> = user_images
-                        .into_iter()
-                        .map(|image_row| mysql::from_value(image_row["name"].clone()))
-                        .collect();
-                    return HttpResponse::Ok().body(
-                        static_interface::get_gallery_page(
-                            &username,
-                            &gallery_name,
-                            user_image_names,
-                        )
-                        .await,
-                    );
+/// One page of a gallery's image rows, sorted and filtered as requested.
+fn gallery_image_rows(
+    galleryid: i32,
+    filter: &image_operations::ImageFilter,
+    request: pagination::PageRequest,
+) -> pagination::Page {
+    let (conditions, mut params) = filter.conditions();
+    params.push((String::from("gallery"), mysql::Value::from(galleryid)));
+    pagination::fetch_page(
+        "images",
+        &format!("gallery=:gallery AND deleted IS NULL{}", conditions),
+        params,
+        filter.sort.keyset(filter.descending),
+        request,
+    )
+}
+
+fn gallery_image_page(
+    galleryid: i32,
+    filter: &image_operations::ImageFilter,
+    request: pagination::PageRequest,
+) -> (Vec<static_interface::ImageDisplay>, pagination::Cursors) {
+    let page = gallery_image_rows(galleryid, filter, request);
+    let images = page
+        .rows
+        .into_iter()
+        .map(|image_row| static_interface::ImageDisplay {
+            name: mysql::from_value(image_row["name"].clone()),
+            title: mysql::from_value(image_row["title"].clone()),
+            caption: mysql::from_value(image_row["caption"].clone()),
+            alt_text: mysql::from_value(image_row["alt_text"].clone()),
+        })
+        .collect();
+    (images, page.cursors)
+}
+
+async fn gallery_response(
+    username: &str,
+    gallery: &str,
+    query: &ImageQuery,
+    page: &PageQuery,
+    listing: &union_structs::ImageListing,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let filter = match image_operations::ImageFilter::from_listing(listing) {
+        Some(filter) => filter,
+        None => return HttpResponse::BadRequest().body(""),
+    };
+    let found = find_requested_gallery(
+        &hr,
+        query.share.as_deref(),
+        query.password.as_deref(),
+        username,
+        gallery,
+    )
+    .await;
+    if let Some((owner, user_gallery, grant)) = found {
+        if grant.as_ref().is_none_or(|grant| grant.image.is_none()) {
+            let username: String = mysql::from_value(owner["username"].clone());
+            let gallery_id: i32 = mysql::from_value(user_gallery["id"].clone());
+            let gallery_name = String::from(gallery);
+            let request = page.page_request();
+            let (user_image_displays, cursors) = gallery_image_page(gallery_id, &filter, request);
+            let (members, children) = match &grant {
+                Some(grant) => {
+                    grant.count_view();
+                    (vec![], vec![])
                 }
-            }
+                None => {
+                    let viewer = authenticate(hr.clone(), Scope::Read).await;
+                    if gallery_operations::is_same_user(viewer.as_ref(), &owner)
+                        || gallery_operations::is_member(viewer.as_ref(), &user_gallery)
+                    {
+                        let members = gallery_members::list_members(gallery_id)
+                            .into_iter()
+                            .map(|(member, role)| (member, String::from(role.name())))
+                            .collect();
+                        (members, gallery_operations::child_galleries(&owner, gallery_id, false))
+                    } else {
+                        (vec![], gallery_operations::child_galleries(&owner, gallery_id, true))
+                    }
+                }
+            };
+            let share = grant.as_ref().map(|grant| grant.token.as_str());
+            let listing = static_interface::ImageListingView {
+                share,
+                sort: filter.sort.name(),
+                descending: filter.descending,
+                links: static_interface::PageLinks::new(
+                    &format!("/u/{}/{}", username, gallery_name),
+                    cursors,
+                    request.limit,
+                    &listing_params(&filter, share),
+                ),
+            };
+            return match static_interface::get_gallery_page(
+                &username,
+                &gallery_name,
+                user_image_displays,
+                members,
+                children,
+                &listing,
+            ) {
+                Some(page) => with_unlock_cookie(HttpResponse::Ok(), &grant).body(page),
+                None => HttpResponse::InternalServerError().body(""),
+            };
         }
     }
     HttpResponse::Ok().body("")
 }
 
+/// The query parameters links to other pages of a gallery keep.
+fn listing_params(
+    filter: &image_operations::ImageFilter,
+    share: Option<&str>,
+) -> Vec<(&'static str, String)> {
+    let mut params = filter.link_params();
+    if let Some(token) = share {
+        params.push(("share", String::from(token)));
+    }
+    params
+}
+
+/// The JSON version of a gallery page, for loading further pages of images. It accepts the same
+/// share token as the page itself, and the share password in the `X-Share-Password` header.
+async fn gallery_listing_response(
+    info: web::Path<PathInfo>,
+    query: web::Query<ImageQuery>,
+    page: web::Query<PageQuery>,
+    listing: web::Query<union_structs::ImageListing>,
+    hr: HttpRequest,
+) -> HttpResponse {
+    let filter = match image_operations::ImageFilter::from_listing(&listing) {
+        Some(filter) => filter,
+        None => return HttpResponse::BadRequest().json(json!({"success": false})),
+    };
+    let gallery = info.path.trim_end_matches('/');
+    let found = find_requested_gallery(
+        &hr,
+        query.share.as_deref(),
+        share_password(&hr).as_deref(),
+        &info.name,
+        gallery,
+    )
+    .await;
+    if let Some((owner, user_gallery, grant)) = found {
+        if grant.as_ref().is_none_or(|grant| grant.image.is_none()) {
+            let username: String = mysql::from_value(owner["username"].clone());
+            let request = page.page_request();
+            let galleryid: i32 = mysql::from_value(user_gallery["id"].clone());
+            let (images, cursors) = gallery_image_page(galleryid, &filter, request);
+            let share = grant.as_ref().map(|grant| grant.token.as_str());
+            let links = static_interface::PageLinks::new(
+                &format!("/list/u/{}/{}", username, gallery),
+                cursors,
+                request.limit,
+                &listing_params(&filter, share),
+            );
+            return with_unlock_cookie(HttpResponse::Ok(), &grant).json(json!({
+                "success": true,
+                "images": static_interface::image_entries(&username, gallery, images, share),
+                "next": cursors.next,
+                "prev": cursors.prev,
+                "next_url": links.next,
+                "prev_url": links.prev,
+            }));
+        }
+    }
+    HttpResponse::NotFound().json(json!({"success": false}))
+}
+
 async fn static_response(info: web::Path<Info>) -> impl Responder {
     let name = if info.name.chars().rev().next().unwrap_or('/') == '/' {
         format!("{}index.html", &info.name)
@@ -344,42 +1153,158 @@ async fn static_response(info: web::Path<Info>) -> impl Responder {
     }
 }
 
-async fn image_server(info: web::Path<ImageServeInfo>, hr: HttpRequest) -> impl Responder {
-    if let Some(user_row) = authenticate(hr).await {
-        let username: String = mysql::from_value(user_row["username"].clone());
-        let userid: i32 = mysql::from_value(user_row["id"].clone());
-        if username == info.name {
-            if let (Some(gallery), Some(image_name)) =
-                (union_structs::parse(&union_structs::GALLERY_REGEX, &info.gallery), union_structs::parse(&union_structs::IMAGETITLE_REGEX, &info.image))
-            {
-                let user_gallery: Vec<mysql::Row> = mysql_init::get_conn()
-                    .exec(
-                        "SELECT * FROM galleries WHERE user=:userid AND name=:galleryname",
-                        params!("userid"=>userid, "galleryname"=>&gallery),
-                    )
-                    .expect("Failed to query user gallery");
-                if user_gallery.len() == 1 {
-                    let gallery_id: i32 = mysql::from_value(user_gallery[0]["id"].clone());
-                    let user_images: Vec<mysql::Row> = mysql_init::get_conn()
-                        .exec(
-                            "SELECT * FROM images WHERE gallery=:gallery AND name=:imagename",
-                            params!("gallery"=>gallery_id, "imagename"=>&image_name),
-                        )
-                        .expect("Failed to select gallery images");
-                    if user_images.len() == 1 {
-                        return HttpResponse::Ok().body(static_interface::get_image(&username, &gallery, &image_name).await.expect("THIS IMAGE FILE SHOULD EXIST"));
-                    }
-                    
-                }
+async fn image_server(info: ImageServeInfo, query: ImageQuery, hr: HttpRequest) -> HttpResponse {
+    let rendition = match query.size.as_deref() {
+        None | Some("original") => None,
+        Some(size) => match image_processing::Rendition::from_name(size) {
+            Some(rendition) => Some(rendition),
+            None => return HttpResponse::BadRequest().body(""),
+        },
+    };
+    let transform = match image_processing::Transform::from_params(
+        query.w,
+        query.h,
+        query.fit.as_deref(),
+        query.format.as_deref(),
+        query.quality,
+    ) {
+        Ok(transform) => transform,
+        Err(_) => return HttpResponse::BadRequest().body(""),
+    };
+    if rendition.is_some() && transform.is_some() {
+        return HttpResponse::BadRequest().body("");
+    }
+    let found = find_requested_gallery(
+        &hr,
+        query.share.as_deref(),
+        query.password.as_deref(),
+        &info.name,
+        &info.gallery,
+    )
+    .await;
+    let image_name = union_structs::parse(&union_structs::IMAGETITLE_REGEX, &info.image);
+    if let (Some((owner, user_gallery, grant)), Some(image_name)) = (found, image_name) {
+        let image_row = match find_gallery_image(&user_gallery, &image_name) {
+            Some(image_row) => image_row,
+            None => return HttpResponse::Ok().body(""),
+        };
+        if let Some(grant) = &grant {
+            let imageid: i32 = mysql::from_value(image_row["id"].clone());
+            match grant.image {
+                Some(shared_image) if shared_image != imageid => return HttpResponse::Ok().body(""),
+                Some(_) => grant.count_view(),
+                None => (),
+            }
+            if !grant.download && !rendition.is_some_and(|rendition| rendition.is_preview()) {
+                return HttpResponse::Forbidden().body("");
             }
         }
+        let username: String = mysql::from_value(owner["username"].clone());
+        let gallery = info.gallery;
+        let content_type = transform
+            .as_ref()
+            .map(|transform| transform.content_type())
+            .unwrap_or("image/jpeg");
+        let image = match (rendition, transform) {
+            (Some(rendition), _) => web::block(move || {
+                image_processing::get_rendition(rendition, &username, &gallery, &image_name)
+            })
+            .await
+            .expect("Rendition task failed"),
+            (_, Some(transform)) => web::block(move || {
+                image_processing::get_transformed(&transform, &username, &gallery, &image_name)
+            })
+            .await
+            .expect("Transform task failed"),
+            (None, None) => static_interface::get_image(&username, &gallery, &image_name)
+                .await
+                .and_then(|image| {
+                    metadata::strip(&image, user_strip_mode(&owner)).map(|(image, _)| image)
+                }),
+        };
+        return match image {
+            Some(image) => with_unlock_cookie(HttpResponse::Ok(), &grant)
+                .content_type(content_type)
+                .body(image),
+            None => HttpResponse::InternalServerError().body(""),
+        };
     }
     HttpResponse::Ok().body("")
 }
 
+async fn image_details(info: ImageServeInfo, hr: HttpRequest) -> HttpResponse {
+    if let Some(user_row) = authenticate(hr, Scope::Read).await {
+        if let Some((username, gallery, image_row)) = find_owned_image(&user_row, &info) {
+            let exif: Option<String> = mysql::from_value(image_row["exif"].clone());
+            return HttpResponse::Ok().json(json!({
+                "success": true,
+                "username": username,
+                "gallery": gallery,
+                "name": mysql::from_value::<String>(image_row["name"].clone()),
+                "size": mysql::from_value::<i64>(image_row["size"].clone()),
+                "width": mysql::from_value::<Option<u32>>(image_row["width"].clone()),
+                "height": mysql::from_value::<Option<u32>>(image_row["height"].clone()),
+                "taken": mysql_init::datetime_string(&image_row["taken"]),
+                "title": mysql::from_value::<Option<String>>(image_row["title"].clone()),
+                "caption": mysql::from_value::<Option<String>>(image_row["caption"].clone()),
+                "alt_text": mysql::from_value::<Option<String>>(image_row["alt_text"].clone()),
+                "exif": exif.and_then(|exif| serde_json::from_str::<Value>(&exif).ok()),
+            }));
+        }
+    }
+    HttpResponse::NotFound().json(json!({"success": false}))
+}
+
+/// Looks up an image in a gallery the viewer is allowed to see, returning the owner's row, the
+/// gallery path and the image row.
+fn find_viewable_image(
+    viewer: Option<&mysql::Row>,
+    info: &ImageServeInfo,
+) -> Option<(mysql::Row, String, mysql::Row)> {
+    let gallery = union_structs::parse_gallery_path(&info.gallery)?;
+    let image_name = union_structs::parse(&union_structs::IMAGETITLE_REGEX, &info.image)?;
+    let (owner, user_gallery) = gallery_operations::find_viewable_gallery(viewer, &info.name, &gallery)?;
+    let image_row = find_gallery_image(&user_gallery, &image_name)?;
+    Some((owner, gallery, image_row))
+}
+
+fn find_gallery_image(gallery_row: &mysql::Row, image_name: &str) -> Option<mysql::Row> {
+    let gallery_id: i32 = mysql::from_value(gallery_row["id"].clone());
+    mysql_init::get_conn()
+        .exec_first(
+            "SELECT * FROM images WHERE gallery=:gallery AND name=:imagename AND deleted IS NULL",
+            params!("gallery"=>gallery_id, "imagename"=>image_name),
+        )
+        .expect("Failed to select gallery images")
+}
+
+/// Looks up an image in one of the authenticated user's galleries, returning the owner's
+/// username, the gallery path and the image row.
+fn find_owned_image(
+    user_row: &mysql::Row,
+    info: &ImageServeInfo,
+) -> Option<(String, String, mysql::Row)> {
+    let (owner, gallery, image_row) = find_viewable_image(Some(user_row), info)?;
+    if gallery_operations::is_same_user(Some(user_row), &owner) {
+        Some((mysql::from_value(owner["username"].clone()), gallery, image_row))
+    } else {
+        None
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     mysql_init::create_tables().expect("Failed to initialize tables");
+    events::start();
+    actix_rt::spawn(async {
+        let mut interval = actix_rt::time::interval(trash::PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = web::block(trash::purge_expired).await {
+                println!("Trash purge failed: {:?}", e);
+            }
+        }
+    });
     let mut config = ServerConfig::new(NoClientAuth::new());
     let cert_file = &mut BufReader::new(File::open(PUBCERT).unwrap());
     let key_file = &mut BufReader::new(File::open(KEY).unwrap());
@@ -392,14 +1317,21 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(|| {
         App::new()
             .service(web::resource("/favicon.ico").route(web::get().to(|| HttpResponse::NotFound())))
+            .service(web::resource("/u/{name}").route(web::get().to(userpage_response)))
             .service(
-                web::resource("/u/{name}/{gallery}/{image}").route(web::get().to(image_server)),
+                web::resource("/u/{name}/{path:.*}")
+                    .route(web::get().to(path_response))
+                    .route(web::post().to(path_password_response)),
             )
+            .service(web::resource("/list/u/{name}").route(web::get().to(user_listing_response)))
             .service(
-                web::resource("/u/{username}/{gallery}").route(web::get().to(gallery_response)),
+                web::resource("/list/u/{name}/{path:.*}")
+                    .route(web::get().to(gallery_listing_response)),
             )
-            .service(web::resource("/u/{name}").route(web::get().to(userpage_response)))
+            .configure(api::configure)
             .service(web::resource("/post/image").route(web::post().to(image_upload_handler)))
+            .service(web::resource("/post/image/{job}").route(web::get().to(upload_job_handler)))
+            .service(web::resource("/ws").route(web::get().to(multiplexed_ws_response)))
             .service(web::resource("/ws/{name}").route(web::get().to(ws_response)))
             .service(web::resource("/{name:.*}").route(web::get().to(static_response)))
     })
@@ -408,3 +1340,36 @@ async fn main() -> std::io::Result<()> {
     .run()
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mysql::consts::ColumnType;
+    use mysql::{Column, Value};
+    use mysql_common::row::new_row;
+
+    fn settings_row(strip_metadata: &str, keep_original_metadata: bool) -> mysql::Row {
+        let columns = vec![
+            Column::new(ColumnType::MYSQL_TYPE_VAR_STRING).with_name(b"strip_metadata"),
+            Column::new(ColumnType::MYSQL_TYPE_TINY).with_name(b"keep_original_metadata"),
+        ];
+        new_row(
+            vec![Value::from(strip_metadata), Value::from(keep_original_metadata)],
+            columns.into(),
+        )
+    }
+
+    #[test]
+    fn contributor_uploads_follow_owner_settings() {
+        let contributor = settings_row("none", true);
+        let owner = settings_row("location", false);
+        let jpeg = metadata::tests::jpeg_with_gps();
+        let latitude = 45u32.to_le_bytes();
+        let (stored, _, original_exif) = strip_upload(&owner, &jpeg).unwrap();
+        assert!(!stored.windows(4).any(|window| window == latitude));
+        assert!(original_exif.is_none());
+        let (unstripped, _, original_exif) = strip_upload(&contributor, &jpeg).unwrap();
+        assert_eq!(unstripped, jpeg);
+        assert!(original_exif.is_none());
+    }
+}
@@ -0,0 +1,516 @@
+use exif::{Exif, In, Tag, Value};
+use serde::Serialize;
+use std::io::Cursor;
+
+/// Metadata parsed from an uploaded image. `taken` is stored in its own column so galleries can
+/// be sorted by capture date; the rest is stored as JSON in the `exif` column.
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub struct ImageMetadata {
+    #[serde(skip)]
+    pub width: Option<u32>,
+    #[serde(skip)]
+    pub height: Option<u32>,
+    #[serde(skip)]
+    pub taken: Option<String>,
+    pub camera: Option<String>,
+    pub lens: Option<String>,
+    pub exposure_time: Option<String>,
+    pub f_number: Option<f64>,
+    pub iso: Option<u32>,
+    pub focal_length: Option<f64>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+fn read_exif(image: &[u8]) -> Option<Exif> {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(image))
+        .ok()
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values
+            .first()
+            .map(|value| String::from_utf8_lossy(value).trim().to_string())
+            .filter(|value| !value.is_empty()),
+        _ => None,
+    }
+}
+
+fn rational_field(exif: &Exif, tag: Tag) -> Option<f64> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) => values.first().map(|value| value.to_f64()),
+        _ => None,
+    }
+}
+
+fn coordinate(exif: &Exif, tag: Tag, reference: Tag, negative: &str) -> Option<f64> {
+    let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(values) if values.len() == 3 => {
+            values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    if ascii_field(exif, reference)? == negative {
+        Some(-degrees)
+    } else {
+        Some(degrees)
+    }
+}
+
+/// Converts an EXIF timestamp (`YYYY:MM:DD HH:MM:SS`) to a MySQL DATETIME literal.
+pub fn exif_datetime_to_sql(datetime: &str) -> Option<String> {
+    let dt = exif::DateTime::from_ascii(datetime.as_bytes()).ok()?;
+    if dt.year == 0 || dt.month == 0 || dt.day == 0 {
+        return None;
+    }
+    Some(format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second
+    ))
+}
+
+pub fn extract_metadata(image: &[u8]) -> ImageMetadata {
+    let (width, height) = match image::io::Reader::new(Cursor::new(image))
+        .with_guessed_format()
+        .ok()
+        .and_then(|reader| reader.into_dimensions().ok())
+    {
+        Some((width, height)) => (Some(width), Some(height)),
+        None => (None, None),
+    };
+    let exif = match read_exif(image) {
+        Some(exif) => exif,
+        None => {
+            return ImageMetadata {
+                width,
+                height,
+                ..Default::default()
+            }
+        }
+    };
+    let camera = match (ascii_field(&exif, Tag::Make), ascii_field(&exif, Tag::Model)) {
+        (Some(make), Some(model)) if model.starts_with(&make) => Some(model),
+        (Some(make), Some(model)) => Some(format!("{} {}", make, model)),
+        (make, model) => model.or(make),
+    };
+    let iso = exif
+        .get_field(Tag::PhotographicSensitivity, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0));
+    ImageMetadata {
+        width,
+        height,
+        taken: ascii_field(&exif, Tag::DateTimeOriginal)
+            .or_else(|| ascii_field(&exif, Tag::DateTime))
+            .and_then(|datetime| exif_datetime_to_sql(&datetime)),
+        camera,
+        lens: ascii_field(&exif, Tag::LensModel),
+        exposure_time: exif
+            .get_field(Tag::ExposureTime, In::PRIMARY)
+            .map(|field| field.display_value().to_string()),
+        f_number: rational_field(&exif, Tag::FNumber),
+        iso,
+        focal_length: rational_field(&exif, Tag::FocalLength),
+        latitude: coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S"),
+        longitude: coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W"),
+    }
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const GPS_IFD_TAG: u16 = 0x8825;
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Which metadata is removed from an image before it is stored or served.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StripMode {
+    None,
+    Location,
+    All,
+}
+
+impl StripMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(StripMode::None),
+            "location" => Some(StripMode::Location),
+            "all" => Some(StripMode::All),
+            _ => None,
+        }
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            StripMode::None => "none",
+            StripMode::Location => "location",
+            StripMode::All => "all",
+        }
+    }
+}
+
+impl ImageMetadata {
+    /// Drops the parsed fields that a strip mode removes from the file itself.
+    pub fn strip(self, mode: StripMode) -> Self {
+        match mode {
+            StripMode::None => self,
+            StripMode::Location => ImageMetadata {
+                latitude: None,
+                longitude: None,
+                ..self
+            },
+            StripMode::All => ImageMetadata {
+                width: self.width,
+                height: self.height,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+struct TiffReader<'a> {
+    tiff: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> TiffReader<'a> {
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.tiff.get(offset)?, *self.tiff.get(offset + 1)?];
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = [
+            *self.tiff.get(offset)?,
+            *self.tiff.get(offset + 1)?,
+            *self.tiff.get(offset + 2)?,
+            *self.tiff.get(offset + 3)?,
+        ];
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+}
+
+fn type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+/// Zeroes the GPS IFD of a TIFF block in place, including any values stored outside of it.
+/// Returns None if the block is malformed.
+fn scrub_gps(tiff: &mut [u8]) -> Option<()> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let reader = TiffReader { tiff: &*tiff, little_endian };
+    let ifd0 = reader.u32_at(4)? as usize;
+    let entries = reader.u16_at(ifd0)? as usize;
+    let gps_ifd = (0..entries)
+        .map(|i| ifd0 + 2 + 12 * i)
+        .find(|&entry| reader.u16_at(entry) == Some(GPS_IFD_TAG))
+        .and_then(|entry| reader.u32_at(entry + 8));
+    let gps_ifd = match gps_ifd {
+        Some(gps_ifd) => gps_ifd as usize,
+        None => return Some(()),
+    };
+    let gps_entries = reader.u16_at(gps_ifd)? as usize;
+    let mut ranges = vec![(gps_ifd + 2, gps_ifd + 2 + 12 * gps_entries)];
+    for i in 0..gps_entries {
+        let entry = gps_ifd + 2 + 12 * i;
+        let size = type_size(reader.u16_at(entry + 2)?).unwrap_or(0) * reader.u32_at(entry + 4)? as usize;
+        if size > 4 {
+            let offset = reader.u32_at(entry + 8)? as usize;
+            ranges.push((offset, offset + size));
+        }
+    }
+    if ranges.iter().any(|&(_, end)| end > tiff.len()) {
+        return None;
+    }
+    for (start, end) in ranges {
+        tiff[start..end].iter_mut().for_each(|byte| *byte = 0);
+    }
+    tiff[gps_ifd] = 0;
+    tiff[gps_ifd + 1] = 0;
+    Some(())
+}
+
+/// A JPEG's marker segments as (marker, whole segment) up to the start of scan, and the remaining
+/// scan data.
+type Segments<'a> = (Vec<(u8, &'a [u8])>, &'a [u8]);
+
+/// Splits a JPEG into its segments. Returns None if the bytes are not a well-formed JPEG.
+fn segments(jpeg: &[u8]) -> Option<Segments<'_>> {
+    if jpeg.get(0..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut segments = vec![];
+    let mut position = 2;
+    loop {
+        if *jpeg.get(position)? != 0xFF {
+            return None;
+        }
+        let marker = *jpeg.get(position + 1)?;
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+        if marker == 0xDA || marker == 0xD9 {
+            return Some((segments, &jpeg[position..]));
+        }
+        let length = u16::from_be_bytes([*jpeg.get(position + 2)?, *jpeg.get(position + 3)?]) as usize;
+        if length < 2 {
+            return None;
+        }
+        segments.push((marker, jpeg.get(position..position + 2 + length)?));
+        position += 2 + length;
+    }
+}
+
+fn is_exif_segment(marker: u8, segment: &[u8]) -> bool {
+    marker == 0xE1 && segment[4..].starts_with(EXIF_HEADER)
+}
+
+/// Returns the EXIF orientation of an image (1 to 8), defaulting to 1 when it is missing.
+pub fn orientation(image: &[u8]) -> u32 {
+    read_exif(image)
+        .and_then(|exif| {
+            exif.get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .filter(|orientation| (1..=8).contains(orientation))
+        .unwrap_or(1)
+}
+
+/// Builds an APP1 segment whose EXIF block holds nothing but the orientation tag.
+fn orientation_segment(orientation: u16) -> Vec<u8> {
+    let mut tiff = vec![b'M', b'M', 0, 42, 0, 0, 0, 8, 0, 1];
+    tiff.extend_from_slice(&ORIENTATION_TAG.to_be_bytes());
+    tiff.extend_from_slice(&3u16.to_be_bytes());
+    tiff.extend_from_slice(&1u32.to_be_bytes());
+    tiff.extend_from_slice(&orientation.to_be_bytes());
+    tiff.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(EXIF_HEADER);
+    segment.extend_from_slice(&tiff);
+    segment
+}
+
+/// Overwrites the orientation tag of a TIFF block in place.
+fn reset_orientation(tiff: &mut [u8]) -> Option<()> {
+    let little_endian = match tiff.get(0..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let reader = TiffReader { tiff: &*tiff, little_endian };
+    let ifd0 = reader.u32_at(4)? as usize;
+    let entries = reader.u16_at(ifd0)? as usize;
+    let entry = (0..entries)
+        .map(|i| ifd0 + 2 + 12 * i)
+        .find(|&entry| reader.u16_at(entry) == Some(ORIENTATION_TAG))?;
+    let value = if little_endian {
+        1u16.to_le_bytes()
+    } else {
+        1u16.to_be_bytes()
+    };
+    tiff.get_mut(entry + 8..entry + 10)?.copy_from_slice(&value);
+    Some(())
+}
+
+/// Copies the EXIF segment of `source` into `target` (which must not have one), with its
+/// orientation reset to 1 since the pixels of `target` are already upright.
+pub fn transplant_exif(source: &[u8], target: &[u8]) -> Option<Vec<u8>> {
+    let (source_segments, _) = segments(source)?;
+    let (target_segments, scan) = segments(target)?;
+    let exif = source_segments
+        .iter()
+        .find(|(marker, segment)| is_exif_segment(*marker, segment))
+        .map(|(_, exif)| {
+            let mut exif = exif.to_vec();
+            let _ = reset_orientation(&mut exif[4 + EXIF_HEADER.len()..]);
+            exif
+        });
+    let insert_at = match target_segments.first() {
+        Some((0xE0, _)) => 1,
+        _ => 0,
+    };
+    let mut transplanted = vec![0xFF, 0xD8];
+    for (i, (_, segment)) in target_segments.iter().enumerate() {
+        if i == insert_at {
+            if let Some(exif) = &exif {
+                transplanted.extend_from_slice(exif);
+            }
+        }
+        transplanted.extend_from_slice(segment);
+    }
+    if insert_at >= target_segments.len() {
+        if let Some(exif) = &exif {
+            transplanted.extend_from_slice(exif);
+        }
+    }
+    transplanted.extend_from_slice(scan);
+    Some(transplanted)
+}
+
+/// Removes metadata from a JPEG without touching the compressed image data. XMP packets are
+/// dropped in both modes since they can repeat the location, and the orientation tag survives
+/// `StripMode::All` so that viewers still display the image upright. Returns the stripped JPEG
+/// and the original EXIF block (a TIFF structure) if there was one, or None if the bytes are not
+/// a JPEG.
+pub fn strip(jpeg: &[u8], mode: StripMode) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+    let (segments, scan) = segments(jpeg)?;
+    if mode == StripMode::None {
+        return Some((jpeg.to_vec(), None));
+    }
+    let mut stripped = vec![0xFF, 0xD8];
+    let mut original_exif = None;
+    for (marker, segment) in segments {
+        let payload = segment.get(4..)?;
+        let keep = match marker {
+            0xE1 if is_exif_segment(marker, segment) => {
+                original_exif = Some(payload[EXIF_HEADER.len()..].to_vec());
+                if mode == StripMode::Location {
+                    let mut scrubbed = segment.to_vec();
+                    if scrub_gps(&mut scrubbed[4 + EXIF_HEADER.len()..]).is_some() {
+                        stripped.extend_from_slice(&scrubbed);
+                    }
+                } else {
+                    match orientation(jpeg) {
+                        1 => (),
+                        orientation => stripped.extend_from_slice(&orientation_segment(orientation as u16)),
+                    }
+                }
+                false
+            }
+            0xE1 if payload.starts_with(XMP_HEADER) => false,
+            0xE0 | 0xE2 | 0xEE => true,
+            0xE1..=0xEF | 0xFE => mode != StripMode::All,
+            _ => true,
+        };
+        if keep {
+            stripped.extend_from_slice(segment);
+        }
+    }
+    stripped.extend_from_slice(scan);
+    Some((stripped, original_exif))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn exif_datetimes() {
+        assert_eq!(
+            exif_datetime_to_sql("2021:07:04 18:30:05"),
+            Some(String::from("2021-07-04 18:30:05"))
+        );
+        vec!["0000:00:00 00:00:00", "2021-07-04", ""]
+            .into_iter()
+            .for_each(|datetime| {
+                assert!(exif_datetime_to_sql(datetime).is_none());
+            });
+    }
+    pub(crate) fn jpeg_with_gps() -> Vec<u8> {
+        let mut tiff = vec![];
+        tiff.extend_from_slice(b"II*\0");
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 with a single GPS pointer entry
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&GPS_IFD_TAG.to_le_bytes());
+        tiff.extend_from_slice(&4u16.to_le_bytes());
+        tiff.extend_from_slice(&1u32.to_le_bytes());
+        tiff.extend_from_slice(&26u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        // GPS IFD with a latitude made of three rationals stored at offset 44
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        tiff.extend_from_slice(&2u16.to_le_bytes());
+        tiff.extend_from_slice(&5u16.to_le_bytes());
+        tiff.extend_from_slice(&3u32.to_le_bytes());
+        tiff.extend_from_slice(&44u32.to_le_bytes());
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        for value in &[45u32, 1, 30, 1, 15, 1] {
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46];
+        jpeg.extend_from_slice(&[0xFF, 0xE1]);
+        jpeg.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        jpeg.extend_from_slice(EXIF_HEADER);
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x04, b'h', b'i']);
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        jpeg
+    }
+    #[test]
+    fn strip_nothing() {
+        let jpeg = jpeg_with_gps();
+        assert_eq!(strip(&jpeg, StripMode::None), Some((jpeg, None)));
+    }
+    #[test]
+    fn strip_location() {
+        let jpeg = jpeg_with_gps();
+        let (stripped, original) = strip(&jpeg, StripMode::Location).unwrap();
+        assert_eq!(stripped.len(), jpeg.len());
+        assert!(original.is_some());
+        assert!(stripped.ends_with(&[0xFF, 0xFE, 0x00, 0x04, b'h', b'i', 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]));
+        assert!(!stripped.windows(4).any(|window| window == 45u32.to_le_bytes()));
+    }
+    #[test]
+    fn strip_all() {
+        let (stripped, original) = strip(&jpeg_with_gps(), StripMode::All).unwrap();
+        assert!(original.is_some());
+        assert_eq!(
+            stripped,
+            vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46, 0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]
+        );
+    }
+    #[test]
+    fn strip_all_keeps_orientation() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend_from_slice(&orientation_segment(6));
+        jpeg.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        assert_eq!(orientation(&jpeg), 6);
+        let (stripped, _) = strip(&jpeg, StripMode::All).unwrap();
+        assert_eq!(orientation(&stripped), 6);
+    }
+    #[test]
+    fn transplant_resets_orientation() {
+        let mut source = vec![0xFF, 0xD8];
+        source.extend_from_slice(&orientation_segment(8));
+        source.extend_from_slice(&[0xFF, 0xDA, 0x00, 0x02, 0x12, 0x34, 0xFF, 0xD9]);
+        let target = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46, 0xFF, 0xDA, 0x00, 0x02, 0x56, 0x78, 0xFF, 0xD9];
+        let transplanted = transplant_exif(&source, &target).unwrap();
+        assert_eq!(orientation(&transplanted), 1);
+        assert!(transplanted.ends_with(&[0x56, 0x78, 0xFF, 0xD9]));
+        assert!(read_exif(&transplanted).is_some());
+    }
+    #[test]
+    fn strip_rejects_non_jpeg() {
+        assert!(strip(b"GIF89a", StripMode::All).is_none());
+    }
+    #[test]
+    fn strip_rejects_short_segments() {
+        for length in 0..2u8 {
+            let jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1, 0x00, length, 0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9];
+            assert!(strip(&jpeg, StripMode::All).is_none());
+            assert!(strip(&jpeg, StripMode::Location).is_none());
+        }
+    }
+    #[test]
+    fn no_exif() {
+        assert_eq!(extract_metadata(b"not an image"), ImageMetadata::default());
+    }
+}
@@ -5,19 +5,72 @@ pub fn get_conn() -> Conn {
     Conn::new(OptsBuilder::new().db_name(Some("uniondb")).user(Some("justus")).pass(Some(""))).expect("Failed to create pool")
 }
 
+/// Formats a DATETIME column value as `YYYY-MM-DD HH:MM:SS`, or None if it is NULL.
+pub fn datetime_string(value: &Value) -> Option<String> {
+    match value {
+        Value::Date(year, month, day, hour, minute, second, _) => Some(format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, hour, minute, second
+        )),
+        Value::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        _ => None,
+    }
+}
+
+/// Adds a column to a table created by an earlier version, given its definition. Whether it
+/// already exists is looked up in `information_schema`, as only MariaDB has `ADD COLUMN IF NOT
+/// EXISTS`.
+fn add_column(conn: &mut Conn, table: &str, column: &str) {
+    let name = column.split_whitespace().next().expect("Column definition without a name");
+    let exists: Option<i32> = conn
+        .exec_first(
+            "SELECT 1 FROM information_schema.COLUMNS WHERE TABLE_SCHEMA=DATABASE() AND TABLE_NAME=:table AND COLUMN_NAME=:name",
+            params!("table"=>table, "name"=>name),
+        )
+        .unwrap_or_else(|_| panic!("Failed to look up columns of {} table.", table));
+    if exists.is_none() {
+        conn.query_drop(format!("ALTER TABLE {} ADD COLUMN {};", table, column))
+            .unwrap_or_else(|_| panic!("Failed to add column to {} table.", table));
+    }
+}
+
 pub fn create_tables() -> Result<()> {
     let mut conn = get_conn();
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS users ( 
         id INT AUTO_INCREMENT PRIMARY KEY, 
         email VARCHAR(128) NOT NULL,
         username VARCHAR(64) NOT NULL,
-        password VARCHAR(255) NOT NULL
+        password VARCHAR(255) NOT NULL,
+        strip_metadata VARCHAR(16) NOT NULL DEFAULT 'none',
+        keep_original_metadata BOOLEAN NOT NULL DEFAULT FALSE,
+        gallery_order VARCHAR(16) NOT NULL DEFAULT 'manual',
+        accessibility_strict BOOLEAN NOT NULL DEFAULT FALSE
     );").expect("Failed to initialize user table.");
+    add_column(&mut conn, "users", "strip_metadata VARCHAR(16) NOT NULL DEFAULT 'none'");
+    add_column(&mut conn, "users", "keep_original_metadata BOOLEAN NOT NULL DEFAULT FALSE");
+    add_column(&mut conn, "users", "gallery_order VARCHAR(16) NOT NULL DEFAULT 'manual'");
+    add_column(&mut conn, "users", "accessibility_strict BOOLEAN NOT NULL DEFAULT FALSE");
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS galleries ( 
         id INT AUTO_INCREMENT PRIMARY KEY, 
         user INT NOT NULL,
-        name VARCHAR(128) NOT NULL
+        name VARCHAR(128) NOT NULL,
+        parent INT,
+        deleted DATETIME,
+        visibility VARCHAR(16) NOT NULL DEFAULT 'private',
+        description TEXT,
+        cover INT,
+        position INT NOT NULL DEFAULT 0,
+        created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+        updated DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
     );").expect("Failed to initialize gallery table.");
+    add_column(&mut conn, "galleries", "parent INT");
+    add_column(&mut conn, "galleries", "deleted DATETIME");
+    add_column(&mut conn, "galleries", "visibility VARCHAR(16) NOT NULL DEFAULT 'private'");
+    add_column(&mut conn, "galleries", "description TEXT");
+    add_column(&mut conn, "galleries", "cover INT");
+    add_column(&mut conn, "galleries", "position INT NOT NULL DEFAULT 0");
+    add_column(&mut conn, "galleries", "created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP");
+    add_column(&mut conn, "galleries", "updated DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP");
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS labels ( 
         id INT AUTO_INCREMENT PRIMARY KEY, 
         user INT NOT NULL,
@@ -26,15 +79,64 @@ pub fn create_tables() -> Result<()> {
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS images ( 
         id INT AUTO_INCREMENT PRIMARY KEY, 
         gallery INT NOT NULL,
-        name VARCHAR(128) NOT NULL
+        name VARCHAR(128) NOT NULL,
+        size BIGINT NOT NULL DEFAULT 0,
+        width INT,
+        height INT,
+        taken DATETIME,
+        exif TEXT,
+        deleted DATETIME,
+        title VARCHAR(256),
+        caption TEXT,
+        alt_text VARCHAR(1000),
+        uploaded DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
     );").expect("Failed to initialize label table.");
+    add_column(&mut conn, "images", "size BIGINT NOT NULL DEFAULT 0");
+    add_column(&mut conn, "images", "width INT");
+    add_column(&mut conn, "images", "height INT");
+    add_column(&mut conn, "images", "taken DATETIME");
+    add_column(&mut conn, "images", "exif TEXT");
+    add_column(&mut conn, "images", "deleted DATETIME");
+    add_column(&mut conn, "images", "title VARCHAR(256)");
+    add_column(&mut conn, "images", "caption TEXT");
+    add_column(&mut conn, "images", "alt_text VARCHAR(1000)");
+    add_column(&mut conn, "images", "uploaded DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP");
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS labelmap ( 
         labelid INT NOT NULL, 
         imageid INT NOT NULL
     );").expect("Failed to initialize label map.");
+    conn.query_drop(r"CREATE TABLE IF NOT EXISTS gallerymembers ( 
+        gallery INT NOT NULL,
+        user INT NOT NULL,
+        role VARCHAR(16) NOT NULL,
+        accepted BOOLEAN NOT NULL DEFAULT FALSE,
+        PRIMARY KEY (gallery, user)
+    );").expect("Failed to initialize gallery member table.");
+    conn.query_drop(r"CREATE TABLE IF NOT EXISTS sharelinks ( 
+        token VARCHAR(48) PRIMARY KEY, 
+        user INT NOT NULL,
+        gallery INT NOT NULL,
+        image INT,
+        expires DATETIME,
+        password VARCHAR(255),
+        unlock_key VARCHAR(64) NOT NULL,
+        download BOOLEAN NOT NULL DEFAULT FALSE,
+        views INT NOT NULL DEFAULT 0,
+        created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );").expect("Failed to initialize share link table.");
     conn.query_drop(r"CREATE TABLE IF NOT EXISTS activesessions ( 
         id VARCHAR(255) PRIMARY KEY, 
         user INT NOT NULL
     );").expect("Failed to initialize active session table.");
+    conn.query_drop(r"CREATE TABLE IF NOT EXISTS apitokens ( 
+        id INT AUTO_INCREMENT PRIMARY KEY, 
+        user INT NOT NULL,
+        name VARCHAR(64) NOT NULL,
+        token_hash CHAR(64) NOT NULL UNIQUE,
+        scopes SET('read', 'upload', 'admin') NOT NULL,
+        expires DATETIME,
+        last_used DATETIME,
+        created DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+    );").expect("Failed to initialize API token table.");
     Ok(())
 }
@@ -0,0 +1,162 @@
+use crate::mysql_init;
+use mysql::prelude::*;
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 200;
+
+/// The columns, or column expressions, a listing is sorted by. The last one is always `id`, so
+/// that every row has its own position and a row can stand in for the place it occupies in the
+/// listing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyset {
+    pub columns: &'static [&'static str],
+    pub descending: bool,
+}
+
+impl Keyset {
+    pub const fn new(columns: &'static [&'static str], descending: bool) -> Self {
+        Keyset { columns, descending }
+    }
+    /// The `ORDER BY` clause for the listing.
+    pub fn order_clause(&self) -> String {
+        self.ordering(false)
+    }
+    fn ordering(&self, backwards: bool) -> String {
+        let direction = if self.descending != backwards { " DESC" } else { "" };
+        self.columns
+            .iter()
+            .map(|column| format!("{}{}", column, direction))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+    fn comparison(&self, backwards: bool) -> &'static str {
+        if self.descending != backwards {
+            "<"
+        } else {
+            ">"
+        }
+    }
+}
+
+/// Which page of a listing to show. Cursors are row ids: `after` is the last row of the page
+/// before the wanted one and `before` is the first row of the page after it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageRequest {
+    pub after: Option<i32>,
+    pub before: Option<i32>,
+    pub limit: u32,
+}
+
+impl PageRequest {
+    /// The limit defaults to `DEFAULT_PAGE_SIZE` and is capped at `MAX_PAGE_SIZE`. When both
+    /// cursors are given, `after` wins.
+    pub fn new(after: Option<i32>, before: Option<i32>, limit: Option<u32>) -> Self {
+        PageRequest {
+            after,
+            before: if after.is_some() { None } else { before },
+            limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+        }
+    }
+}
+
+/// Where the pages next to a fetched page start, if there are any.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cursors {
+    pub next: Option<i32>,
+    pub prev: Option<i32>,
+}
+
+pub struct Page {
+    pub rows: Vec<mysql::Row>,
+    pub cursors: Cursors,
+}
+
+/// The next and previous cursors for a page holding rows `ids`, in listing order. `more` says
+/// whether there were rows beyond the page in the direction it was fetched in.
+fn cursors(ids: &[i32], more: bool, request: &PageRequest) -> Cursors {
+    let (first, last) = (ids.first().copied(), ids.last().copied());
+    if request.before.is_some() {
+        Cursors {
+            next: last.or(request.before),
+            prev: if more { first } else { None },
+        }
+    } else {
+        Cursors {
+            next: if more { last } else { None },
+            prev: request.after.and(first),
+        }
+    }
+}
+
+/// Fetches one page of `SELECT * FROM {table} WHERE {filter}` sorted by `keyset`. A cursor that
+/// no longer matches a row yields an empty page.
+pub fn fetch_page(
+    table: &str,
+    filter: &str,
+    mut params: Vec<(String, mysql::Value)>,
+    keyset: Keyset,
+    request: PageRequest,
+) -> Page {
+    let backwards = request.before.is_some();
+    let cursor_filter = match request.after.or(request.before) {
+        Some(cursor) => {
+            params.push((String::from("cursor"), mysql::Value::from(cursor)));
+            format!(
+                " AND ({columns}) {} (SELECT {columns} FROM {} WHERE id=:cursor)",
+                keyset.comparison(backwards),
+                table,
+                columns = keyset.columns.join(", "),
+            )
+        }
+        None => String::new(),
+    };
+    let query = format!(
+        "SELECT * FROM {} WHERE {}{} ORDER BY {} LIMIT {}",
+        table,
+        filter,
+        cursor_filter,
+        keyset.ordering(backwards),
+        request.limit + 1,
+    );
+    let mut rows: Vec<mysql::Row> = mysql_init::get_conn()
+        .exec(query, params)
+        .expect("Failed to fetch page");
+    let more = rows.len() > request.limit as usize;
+    rows.truncate(request.limit as usize);
+    if backwards {
+        rows.reverse();
+    }
+    let ids: Vec<i32> = rows.iter().map(|row| mysql::from_value(row["id"].clone())).collect();
+    let cursors = cursors(&ids, more, &request);
+    Page { rows, cursors }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn page_cursors() {
+        let first = PageRequest::new(None, None, None);
+        assert_eq!(first.limit, DEFAULT_PAGE_SIZE);
+        assert_eq!(cursors(&[1, 2, 3], true, &first), Cursors { next: Some(3), prev: None });
+        assert_eq!(cursors(&[1, 2, 3], false, &first), Cursors { next: None, prev: None });
+        let after = PageRequest::new(Some(3), Some(9), Some(1000));
+        assert_eq!(after.before, None);
+        assert_eq!(after.limit, MAX_PAGE_SIZE);
+        assert_eq!(cursors(&[4, 5], false, &after), Cursors { next: None, prev: Some(4) });
+        let before = PageRequest::new(None, Some(4), Some(0));
+        assert_eq!(before.limit, 1);
+        assert_eq!(cursors(&[3], true, &before), Cursors { next: Some(3), prev: Some(3) });
+        assert_eq!(cursors(&[], false, &before), Cursors { next: Some(4), prev: None });
+    }
+
+    #[test]
+    fn keyset_order() {
+        let keyset = Keyset::new(&["created", "id"], true);
+        assert_eq!(keyset.order_clause(), "created DESC, id DESC");
+        assert_eq!(keyset.ordering(true), "created, id");
+        assert_eq!(keyset.comparison(false), "<");
+        assert_eq!(Keyset::new(&["id"], false).comparison(true), "<");
+    }
+}
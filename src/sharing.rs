@@ -0,0 +1,200 @@
+use crate::union_structs::{self, SessionRequest, ShareCreate, ShareRevoke};
+use crate::{
+    authenticate_with_id, gallery_operations, hash_password, mysql_init, random_id,
+    verify_password,
+};
+use actix_web::cookie::Cookie;
+use actix_web::HttpRequest;
+use mysql::params;
+use mysql::prelude::*;
+use serde_json::{json, Value};
+
+/// Access to a gallery, or a single image in it, granted by a share token instead of a session.
+pub struct ShareGrant {
+    pub token: String,
+    pub owner: mysql::Row,
+    pub gallery: mysql::Row,
+    pub image: Option<i32>,
+    pub download: bool,
+    unlock_cookie: Option<Cookie<'static>>,
+}
+
+impl ShareGrant {
+    /// The cookie that lets the follow-up image requests of a password-protected share skip the
+    /// password, if the password was just checked.
+    pub fn unlock_cookie(&self) -> Option<Cookie<'static>> {
+        self.unlock_cookie.clone()
+    }
+    pub fn count_view(&self) {
+        mysql_init::get_conn()
+            .exec_drop(
+                "UPDATE sharelinks SET views=views+1 WHERE token=:token",
+                params!("token"=>&self.token),
+            )
+            .expect("Failed to count share view");
+    }
+}
+
+fn unlock_cookie_name(token: &str) -> String {
+    format!("share_{}", token)
+}
+
+/// Resolves a share token, checking its expiry and, if it has one, its password. The password can
+/// come from the request's header or form, or from the unlock cookie set after an earlier
+/// successful check.
+pub fn resolve_share(hr: &HttpRequest, token: &str, password: Option<&str>) -> Option<ShareGrant> {
+    let token = union_structs::parse(&union_structs::SHARE_TOKEN_REGEX, token)?;
+    let mut conn = mysql_init::get_conn();
+    let share: mysql::Row = conn
+        .exec_first(
+            "SELECT * FROM sharelinks WHERE token=:token AND (expires IS NULL OR expires > NOW())",
+            params!("token"=>&token),
+        )
+        .expect("Failed to query share link")?;
+    let password_hash: Option<String> = mysql::from_value(share["password"].clone());
+    let unlock_key: String = mysql::from_value(share["unlock_key"].clone());
+    let mut unlock_cookie = None;
+    if let Some(password_hash) = password_hash {
+        let unlocked = hr
+            .cookie(&unlock_cookie_name(&token))
+            .is_some_and(|cookie| cookie.value() == unlock_key);
+        if !unlocked {
+            if !verify_password(password?, &password_hash) {
+                return None;
+            }
+            unlock_cookie = Some(
+                Cookie::build(unlock_cookie_name(&token), unlock_key)
+                    .path("/u/")
+                    .http_only(true)
+                    .finish(),
+            );
+        }
+    }
+    let galleryid: i32 = mysql::from_value(share["gallery"].clone());
+    let gallery: mysql::Row = conn
+        .exec_first(
+            "SELECT * FROM galleries WHERE id=:galleryid AND deleted IS NULL",
+            params!("galleryid"=>galleryid),
+        )
+        .expect("Failed to query shared gallery")?;
+    let userid: i32 = mysql::from_value(share["user"].clone());
+    let owner: mysql::Row = conn
+        .exec_first("SELECT * FROM users WHERE id=:userid", params!("userid"=>userid))
+        .expect("Failed to query share owner")?;
+    Some(ShareGrant {
+        token,
+        owner,
+        gallery,
+        image: mysql::from_value(share["image"].clone()),
+        download: mysql::from_value(share["download"].clone()),
+        unlock_cookie,
+    })
+}
+
+pub fn handle_share_creation(json: Value) -> Value {
+    let share: ShareCreate = match serde_json::from_value(json) {
+        Ok(share) => share,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(gallery_name), Ok(image_name), Ok(expires), Ok(password)) = (
+        share.get_id().and_then(authenticate_with_id),
+        share.get_gallery_name(),
+        share.get_image_name(),
+        share.get_expires(),
+        share.get_password(),
+    ) {
+        let userid: i32 = mysql::from_value(user_row["id"].clone());
+        let galleryid = match gallery_operations::find_gallery_id(userid, &gallery_name) {
+            Some(galleryid) => galleryid,
+            None => return json!({"success": false}),
+        };
+        let imageid: Option<i32> = match &image_name {
+            Some(image_name) => match mysql_init::get_conn()
+                .exec_first(
+                    "SELECT id FROM images WHERE gallery=:galleryid AND name=:imagename AND deleted IS NULL",
+                    params!("galleryid"=>galleryid, "imagename"=>image_name),
+                )
+                .expect("Failed to find shared image")
+            {
+                Some(imageid) => Some(imageid),
+                None => return json!({"success": false}),
+            },
+            None => None,
+        };
+        let token = random_id(48);
+        mysql_init::get_conn()
+            .exec_drop(
+                "INSERT INTO sharelinks(token, user, gallery, image, expires, password, unlock_key, download) VALUES (:token, :user, :gallery, :image, :expires, :password, :unlock, :download)",
+                params!(
+                    "token"=>&token,
+                    "user"=>userid,
+                    "gallery"=>galleryid,
+                    "image"=>imageid,
+                    "expires"=>&expires,
+                    "password"=>password.map(|password| hash_password(&password)),
+                    "unlock"=>random_id(64),
+                    "download"=>share.get_download(),
+                ),
+            )
+            .expect("Failed to create share link");
+        let username: String = mysql::from_value(user_row["username"].clone());
+        let url = match image_name {
+            Some(image_name) => format!("/u/{}/{}/{}?share={}", username, gallery_name, image_name, token),
+            None => format!("/u/{}/{}?share={}", username, gallery_name, token),
+        };
+        return json!({"success": true, "token": token, "url": url});
+    }
+    json!({"success": false})
+}
+
+pub fn handle_share_list(json: Value) -> Value {
+    let user_row = match serde_json::from_value::<SessionRequest>(json)
+        .ok()
+        .and_then(|request| request.get_id())
+        .and_then(authenticate_with_id)
+    {
+        Some(user_row) => user_row,
+        None => return json!({"success": false}),
+    };
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let shares: Vec<Value> = mysql_init::get_conn()
+        .exec_map(
+            "SELECT sharelinks.*, images.name AS imagename FROM sharelinks LEFT JOIN images ON sharelinks.image=images.id WHERE sharelinks.user=:userid ORDER BY sharelinks.created DESC",
+            params!("userid"=>userid),
+            |row: mysql::Row| {
+                json!({
+                    "token": mysql::from_value::<String>(row["token"].clone()),
+                    "gallery_name": gallery_operations::gallery_path(mysql::from_value(row["gallery"].clone())),
+                    "image_name": mysql::from_value::<Option<String>>(row["imagename"].clone()),
+                    "expires": mysql_init::datetime_string(&row["expires"]),
+                    "password": mysql::from_value::<Option<String>>(row["password"].clone()).is_some(),
+                    "download": mysql::from_value::<bool>(row["download"].clone()),
+                    "views": mysql::from_value::<i32>(row["views"].clone()),
+                    "created": mysql_init::datetime_string(&row["created"]),
+                })
+            },
+        )
+        .expect("Failed to list share links");
+    json!({"success": true, "shares": shares})
+}
+
+pub fn handle_share_revocation(json: Value) -> Value {
+    let revoke: ShareRevoke = match serde_json::from_value(json) {
+        Ok(revoke) => revoke,
+        Err(_) => return json!({"success": false}),
+    };
+    if let (Some(user_row), Some(token)) = (
+        revoke.get_id().and_then(authenticate_with_id),
+        revoke.get_token(),
+    ) {
+        let userid: i32 = mysql::from_value(user_row["id"].clone());
+        let mut conn = mysql_init::get_conn();
+        conn.exec_drop(
+            "DELETE FROM sharelinks WHERE token=:token AND user=:userid",
+            params!("token"=>&token, "userid"=>userid),
+        )
+        .expect("Failed to revoke share link");
+        return json!({"success": conn.affected_rows() == 1});
+    }
+    json!({"success": false})
+}
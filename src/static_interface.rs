@@ -1,6 +1,10 @@
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use std::io::prelude::*;
+use crate::pagination::Cursors;
+use crate::templates;
+use serde_json::{json, Value};
+use tera::Context;
 
 async fn get_file(url: String) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let mut file = File::open(url).await?;
@@ -29,41 +33,158 @@ pub async fn get_image(username: &str, gallery: &str, image_title: &str) -> Opti
         .expect(&format!("Failed to open file {}", url)))
 }
 
-pub async fn get_user_page(username: &str, gallery_names: Vec<String>) -> String {
-    let user_template = get_file_string(String::from("/var/static/users.html"))
-        .await
-        .expect("Failed to find users.html");
-    let split_template: Vec<&str> = user_template.split('$').collect();
-    let mut split_file = vec![split_template[0], username, split_template[1]];
-    let mut gallery_displays = vec![];
-    for gallery_name in gallery_names {
-        let gallery_url = format!("/u/{}/{}", username, gallery_name);
-        let split_gallery_display = vec![split_template[2], &gallery_url, split_template[3], &gallery_name, split_template[4]];
-        let gallery_display: String = split_gallery_display.into_iter().collect();
-        gallery_displays.push(gallery_display);
-    }
-    for i in 0..gallery_displays.len() {
-        split_file.push(&gallery_displays[i]);
-    }
-    split_file.push(split_template[5]);
-    split_file.into_iter().collect()
-}
-
-pub async fn get_gallery_page(username: &str, gallery: &str, images: Vec<String>) -> String {
-    let user_template = get_file_string(String::from("/var/static/gallery.html")).await.expect("Failed to find gallery.html");
-    let split_template: Vec<&str> = user_template.split('$').collect();
-    let mut split_file = vec![split_template[0], username, split_template[1], gallery, split_template[2]];
-    let mut image_displays: Vec<String> = vec![];
-    for image in images {
-        let image_url = format!("/u/{}/{}/{}", username, gallery, image);
-        let split_image_display = vec![split_template[3], &image, split_template[4], &image_url, split_template[5]];
-        image_displays.push(split_image_display.into_iter().collect());
-    }
-    for i in 0..image_displays.len() {
-        split_file.push(&image_displays[i]);
+/// Links to the pages next to the one shown, at the same page size.
+#[derive(Default)]
+pub struct PageLinks {
+    pub next: Option<String>,
+    pub prev: Option<String>,
+}
+
+impl PageLinks {
+    /// `params` are further query parameters every link keeps, such as a share token or filters.
+    pub fn new(url: &str, cursors: Cursors, limit: u32, params: &[(&str, String)]) -> Self {
+        let extra: String = params
+            .iter()
+            .map(|(name, value)| format!("&{}={}", name, encode_query_value(value)))
+            .collect();
+        let link = |cursor: &str, id: i32| format!("{}?{}={}&limit={}{}", url, cursor, id, limit, extra);
+        PageLinks {
+            next: cursors.next.map(|id| link("after", id)),
+            prev: cursors.prev.map(|id| link("before", id)),
+        }
     }
-    split_file.push(split_template[6]);
-    split_file.into_iter().collect()
+}
+
+/// Percent-encodes everything but unreserved characters.
+fn encode_query_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// What the user page shows for each gallery.
+pub struct GalleryDisplay {
+    pub name: String,
+    pub description: Option<String>,
+    pub cover_url: Option<String>,
+}
+
+/// The galleries of a user page, as shown by users.html and the JSON listing.
+pub fn gallery_entries(username: &str, galleries: Vec<GalleryDisplay>) -> Vec<Value> {
+    galleries
+        .into_iter()
+        .map(|gallery| {
+            json!({
+                "url": format!("/u/{}/{}", username, gallery.name),
+                "name": gallery.name,
+                "cover_url": gallery.cover_url,
+                "description": gallery.description,
+            })
+        })
+        .collect()
+}
+
+fn user_page_context(username: &str, galleries: Vec<GalleryDisplay>, links: &PageLinks) -> Context {
+    Context::from_serialize(json!({
+        "username": username,
+        "galleries": gallery_entries(username, galleries),
+        "next_url": links.next,
+        "prev_url": links.prev,
+    }))
+    .expect("Failed to build user page context")
+}
+
+/// Renders users.html for one page of a user's galleries.
+pub fn get_user_page(username: &str, galleries: Vec<GalleryDisplay>, links: &PageLinks) -> Option<String> {
+    templates::render("users.html", &user_page_context(username, galleries, links))
+}
+
+/// What the gallery page shows for each image.
+pub struct ImageDisplay {
+    pub name: String,
+    pub title: Option<String>,
+    pub caption: Option<String>,
+    pub alt_text: Option<String>,
+}
+
+/// The images of a gallery page, as shown by gallery.html and the JSON listing. Image links carry
+/// the share token when the gallery is viewed through a share link.
+pub fn image_entries(username: &str, gallery: &str, images: Vec<ImageDisplay>, share: Option<&str>) -> Vec<Value> {
+    images
+        .into_iter()
+        .map(|image| {
+            let url = match share {
+                Some(token) => format!("/u/{}/{}/{}?size=thumb&share={}", username, gallery, image.name, token),
+                None => format!("/u/{}/{}/{}?size=thumb", username, gallery, image.name),
+            };
+            let title = image.title.clone().unwrap_or_else(|| image.name.clone());
+            json!({
+                "url": url,
+                "title": title,
+                "name": image.name,
+                "caption": image.caption,
+                "alt_text": image.alt_text.unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+/// How the images on a gallery page were listed: the share token they were reached through, the
+/// name of their sort, whether it descends and the links to other pages.
+pub struct ImageListingView<'a> {
+    pub share: Option<&'a str>,
+    pub sort: &'a str,
+    pub descending: bool,
+    pub links: PageLinks,
+}
+
+fn gallery_page_context(username: &str, gallery: &str, images: Vec<ImageDisplay>, members: Vec<(String, String)>, children: Vec<String>, listing: &ImageListingView) -> Context {
+    let mut crumb_path = String::new();
+    let breadcrumbs: Vec<Value> = gallery
+        .split('/')
+        .map(|crumb| {
+            if !crumb_path.is_empty() {
+                crumb_path.push('/');
+            }
+            crumb_path.push_str(crumb);
+            json!({"name": crumb, "url": format!("/u/{}/{}", username, crumb_path)})
+        })
+        .collect();
+    let members: Vec<Value> = members
+        .into_iter()
+        .map(|(member, role)| json!({"username": member, "role": role}))
+        .collect();
+    let children: Vec<Value> = children
+        .into_iter()
+        .map(|child| json!({"url": format!("/u/{}/{}/{}", username, gallery, child), "name": child}))
+        .collect();
+    Context::from_serialize(json!({
+        "username": username,
+        "gallery": gallery,
+        "breadcrumbs": breadcrumbs,
+        "images": image_entries(username, gallery, images, listing.share),
+        "members": members,
+        "children": children,
+        "share": listing.share,
+        "sort": listing.sort,
+        "order": if listing.descending { "desc" } else { "asc" },
+        "next_url": listing.links.next,
+        "prev_url": listing.links.prev,
+    }))
+    .expect("Failed to build gallery page context")
+}
+
+/// Renders gallery.html for one page of a gallery's images. Members are (username, role) pairs
+/// and children are the names of the galleries nested in this one.
+pub fn get_gallery_page(username: &str, gallery: &str, images: Vec<ImageDisplay>, members: Vec<(String, String)>, children: Vec<String>, listing: &ImageListingView) -> Option<String> {
+    templates::render(
+        "gallery.html",
+        &gallery_page_context(username, gallery, images, members, children, listing),
+    )
 }
 
 pub fn make_user_dir(username: String) {
@@ -74,7 +195,163 @@ pub fn make_gallery_dir(username: String, galleryname: String) {
     std::fs::create_dir_all(format!("/var/static/root/u/{}/{}", username, galleryname)).expect("Failed to create gallery dir");
 }
 
-pub fn make_image(username: String, galleryname: String, imagetitle: String, image: String) {
+pub fn rename_gallery_dir(username: &str, from_gallery: &str, to_gallery: &str) {
+    std::fs::rename(
+        format!("/var/static/root/u/{}/{}", username, from_gallery),
+        format!("/var/static/root/u/{}/{}", username, to_gallery),
+    ).expect("Failed to rename gallery dir");
+    let private_dir = format!("/var/static/private/u/{}/{}", username, from_gallery);
+    if std::path::Path::new(&private_dir).exists() {
+        std::fs::rename(private_dir, format!("/var/static/private/u/{}/{}", username, to_gallery)).expect("Failed to rename private metadata dir");
+    }
+}
+
+pub fn trash_gallery_dir(username: &str, galleryname: &str, galleryid: i32) {
+    std::fs::create_dir_all("/var/static/trash/galleries").expect("Failed to create trash dir");
+    std::fs::rename(
+        format!("/var/static/root/u/{}/{}", username, galleryname),
+        format!("/var/static/trash/galleries/{}", galleryid),
+    ).expect("Failed to move gallery dir to trash");
+    let private_dir = format!("/var/static/private/u/{}/{}", username, galleryname);
+    if std::path::Path::new(&private_dir).exists() {
+        std::fs::rename(private_dir, format!("/var/static/trash/galleries/{}.private", galleryid)).expect("Failed to move private metadata dir to trash");
+    }
+}
+
+pub fn restore_gallery_dir(username: &str, galleryname: &str, galleryid: i32) {
+    std::fs::rename(
+        format!("/var/static/trash/galleries/{}", galleryid),
+        format!("/var/static/root/u/{}/{}", username, galleryname),
+    ).expect("Failed to restore gallery dir from trash");
+    let private_dir = format!("/var/static/trash/galleries/{}.private", galleryid);
+    if std::path::Path::new(&private_dir).exists() {
+        std::fs::rename(private_dir, format!("/var/static/private/u/{}/{}", username, galleryname)).expect("Failed to restore private metadata dir from trash");
+    }
+}
+
+pub fn remove_trashed_gallery(galleryid: i32) {
+    let _ = std::fs::remove_dir_all(format!("/var/static/trash/galleries/{}", galleryid));
+    let _ = std::fs::remove_dir_all(format!("/var/static/trash/galleries/{}.private", galleryid));
+}
+
+pub fn trash_image(username: &str, galleryname: &str, imagetitle: &str, imageid: i32) {
+    std::fs::create_dir_all("/var/static/trash/images").expect("Failed to create trash dir");
+    std::fs::rename(
+        format!("/var/static/root/u/{}/{}/{}", username, galleryname, imagetitle),
+        format!("/var/static/trash/images/{}", imageid),
+    ).expect("Failed to move image to trash");
+    let private_metadata = private_metadata_path(username, galleryname, imagetitle);
+    if std::path::Path::new(&private_metadata).exists() {
+        std::fs::rename(private_metadata, format!("/var/static/trash/images/{}.exif", imageid)).expect("Failed to move private metadata to trash");
+    }
+}
+
+pub fn restore_image(username: &str, galleryname: &str, imagetitle: &str, imageid: i32) {
+    std::fs::rename(
+        format!("/var/static/trash/images/{}", imageid),
+        format!("/var/static/root/u/{}/{}/{}", username, galleryname, imagetitle),
+    ).expect("Failed to restore image from trash");
+    let private_metadata = format!("/var/static/trash/images/{}.exif", imageid);
+    if std::path::Path::new(&private_metadata).exists() {
+        std::fs::create_dir_all(format!("/var/static/private/u/{}/{}", username, galleryname)).expect("Failed to create private metadata dir");
+        std::fs::rename(private_metadata, private_metadata_path(username, galleryname, imagetitle)).expect("Failed to restore private metadata from trash");
+    }
+}
+
+pub fn remove_trashed_image(imageid: i32) {
+    let _ = std::fs::remove_file(format!("/var/static/trash/images/{}", imageid));
+    let _ = std::fs::remove_file(format!("/var/static/trash/images/{}.exif", imageid));
+}
+
+pub fn decode_image(image: &str) -> Option<Vec<u8>> {
+    base64::decode(image.split("image/jpeg;base64,").skip(1).next()?).ok()
+}
+
+pub fn load_image(username: &str, galleryname: &str, imagetitle: &str) -> Option<Vec<u8>> {
+    std::fs::read(format!("/var/static/root/u/{}/{}/{}", username, galleryname, imagetitle)).ok()
+}
+
+pub fn make_image(username: String, galleryname: String, imagetitle: String, image: &[u8]) {
     let mut image_file = std::fs::File::create(format!("/var/static/root/u/{}/{}/{}", username, galleryname, imagetitle)).expect("Failed to create image file");
-    image_file.write_all(&base64::decode(image.split("image/jpeg;base64,").skip(1).next().expect("Bad format for image")).expect("Failed to decode image")).expect("Failed to save image file");
+    image_file.write_all(image).expect("Failed to save image file");
+}
+pub fn make_private_metadata(username: &str, galleryname: &str, imagetitle: &str, exif: &[u8]) {
+    std::fs::create_dir_all(format!("/var/static/private/u/{}/{}", username, galleryname)).expect("Failed to create private metadata dir");
+    std::fs::write(private_metadata_path(username, galleryname, imagetitle), exif).expect("Failed to save private metadata");
+}
+
+fn private_metadata_path(username: &str, galleryname: &str, imagetitle: &str) -> String {
+    format!("/var/static/private/u/{}/{}/{}.exif", username, galleryname, imagetitle)
+}
+
+/// Moves an image file and its private metadata. If the metadata can't be moved, the image file
+/// is moved back so that nothing has changed when this fails.
+pub fn move_image(username: &str, from_gallery: &str, from_title: &str, to_gallery: &str, to_title: &str) -> std::io::Result<()> {
+    let from = format!("/var/static/root/u/{}/{}/{}", username, from_gallery, from_title);
+    let to = format!("/var/static/root/u/{}/{}/{}", username, to_gallery, to_title);
+    std::fs::rename(&from, &to)?;
+    let private_metadata = private_metadata_path(username, from_gallery, from_title);
+    if std::path::Path::new(&private_metadata).exists() {
+        let moved = std::fs::create_dir_all(format!("/var/static/private/u/{}/{}", username, to_gallery))
+            .and_then(|_| std::fs::rename(&private_metadata, private_metadata_path(username, to_gallery, to_title)));
+        if let Err(e) = moved {
+            std::fs::rename(&to, &from).expect("Failed to move image file back");
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tera::Tera;
+
+    fn repo_templates() -> Tera {
+        Tera::new(concat!(env!("CARGO_MANIFEST_DIR"), "/templates/**/*.html")).expect("Failed to load templates")
+    }
+
+    #[test]
+    fn page_links_keep_parameters() {
+        let params = [("sort", String::from("taken")), ("to", String::from("2022-06-30 23:59:59"))];
+        let links = PageLinks::new("/u/user/trips", Cursors { next: Some(9), prev: Some(3) }, 50, &params);
+        assert_eq!(links.next.as_deref(), Some("/u/user/trips?after=9&limit=50&sort=taken&to=2022-06-30%2023%3A59%3A59"));
+        assert_eq!(links.prev.as_deref(), Some("/u/user/trips?before=3&limit=50&sort=taken&to=2022-06-30%2023%3A59%3A59"));
+    }
+
+    #[test]
+    fn pages_escape_user_text() {
+        let tera = repo_templates();
+        let images = vec![ImageDisplay {
+            name: String::from("beach.png"),
+            title: Some(String::from("<script>alert(1)</script>")),
+            caption: Some(String::from("Sand & \"sun\"")),
+            alt_text: None,
+        }];
+        let members = vec![(String::from("friend"), String::from("viewer"))];
+        let listing = ImageListingView {
+            share: Some("token"),
+            sort: "uploaded",
+            descending: false,
+            links: PageLinks::new("/u/user/trips/summer", Cursors { next: Some(7), prev: None }, 20, &[("share", String::from("token"))]),
+        };
+        let context = gallery_page_context("user", "trips/summer", images, members, vec![String::from("day1")], &listing);
+        let page = tera.render("gallery.html", &context).expect("Failed to render gallery page");
+        assert!(!page.contains("<script>alert(1)"));
+        assert!(page.contains("&lt;script&gt;alert(1)"));
+        assert!(page.contains("Sand &amp; &quot;sun&quot;"));
+        // Tera also escapes slashes, which browsers decode in attributes.
+        assert!(page.contains("&#x2F;u&#x2F;user&#x2F;trips&#x2F;summer&#x2F;beach.png?size=thumb&amp;share=token"));
+        assert!(page.contains("&#x2F;u&#x2F;user&#x2F;trips&#x2F;summer&#x2F;day1"));
+        assert!(page.contains(">friend</a> (viewer)"));
+        assert!(page.contains("summer?after=7&amp;limit=20&amp;share=token"));
+
+        let galleries = vec![GalleryDisplay {
+            name: String::from("trips"),
+            description: Some(String::from("<b>Holidays</b>")),
+            cover_url: None,
+        }];
+        let page = tera.render("users.html", &user_page_context("user", galleries, &PageLinks::default())).expect("Failed to render user page");
+        assert!(page.contains("&lt;b&gt;Holidays&lt;&#x2F;b&gt;"));
+    }
 }
@@ -0,0 +1,70 @@
+use lazy_static::lazy_static;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use tera::{Context, Tera};
+
+lazy_static! {
+    static ref TEMPLATE_DIR: String = std::env::var("UNION_TEMPLATE_DIR")
+        .unwrap_or_else(|_| String::from("/var/static/templates"));
+    /// Reloads templates whenever their files change, for working on them without restarts.
+    static ref DEV_MODE: bool = std::env::var("UNION_DEV_MODE")
+        .map(|value| value == "1" || value == "true")
+        .unwrap_or(false);
+    static ref TEMPLATES: RwLock<LoadedTemplates> = RwLock::new(load());
+}
+
+struct LoadedTemplates {
+    tera: Tera,
+    loaded: SystemTime,
+}
+
+fn load() -> LoadedTemplates {
+    let loaded = SystemTime::now();
+    let tera = Tera::new(&format!("{}/**/*.html", *TEMPLATE_DIR)).expect("Failed to load templates");
+    LoadedTemplates { tera, loaded }
+}
+
+/// The most recent modification time of any file under a directory.
+fn last_modified(dir: &Path) -> Option<SystemTime> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.is_dir() {
+                last_modified(&path)
+            } else {
+                entry.metadata().and_then(|metadata| metadata.modified()).ok()
+            }
+        })
+        .max()
+}
+
+fn reload_if_changed() {
+    let changed = last_modified(Path::new(TEMPLATE_DIR.as_str())).is_some_and(|modified| {
+        modified > TEMPLATES.read().expect("Templates poisoned").loaded
+    });
+    if changed {
+        let mut templates = TEMPLATES.write().expect("Templates poisoned");
+        templates.loaded = SystemTime::now();
+        if let Err(e) = templates.tera.full_reload() {
+            println!("Failed to reload templates: {:?}", e);
+        }
+    }
+}
+
+/// Renders a template from the template directory. Values are HTML-escaped unless a template
+/// marks them `safe`.
+pub fn render(name: &str, context: &Context) -> Option<String> {
+    if *DEV_MODE {
+        reload_if_changed();
+    }
+    match TEMPLATES.read().expect("Templates poisoned").tera.render(name, context) {
+        Ok(html) => Some(html),
+        Err(e) => {
+            println!("Failed to render {}: {:?}", name, e);
+            None
+        }
+    }
+}
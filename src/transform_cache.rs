@@ -0,0 +1,193 @@
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Mutex;
+
+const TRANSFORM_CACHE_ROOT: &str = "/var/static/cache/transform";
+const DEFAULT_CAPACITY: u64 = 1024 * 1024 * 1024;
+
+lazy_static! {
+    static ref TRANSFORM_CACHE: Mutex<LruIndex> = Mutex::new(load_index());
+}
+
+/// Tracks the size and recency of cached files so the least recently used ones can be evicted
+/// once the cache grows past its capacity.
+pub struct LruIndex {
+    capacity: u64,
+    used: u64,
+    tick: u64,
+    entries: HashMap<String, (u64, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl LruIndex {
+    pub fn new(capacity: u64) -> Self {
+        LruIndex {
+            capacity,
+            used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+    /// Marks a key as just used, returning false if it is not in the index.
+    pub fn touch(&mut self, key: &str) -> bool {
+        if let Some((size, last_used)) = self.entries.get(key).copied() {
+            self.order.remove(&last_used);
+            self.tick += 1;
+            self.order.insert(self.tick, String::from(key));
+            self.entries.insert(String::from(key), (size, self.tick));
+            true
+        } else {
+            false
+        }
+    }
+    /// Adds a key to the index and returns the keys that had to be evicted to make room for it.
+    pub fn insert(&mut self, key: String, size: u64) -> Vec<String> {
+        self.remove(&key);
+        self.tick += 1;
+        self.used += size;
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key.clone(), (size, self.tick));
+        let mut evicted = vec![];
+        while self.used > self.capacity {
+            let (&oldest, _) = self.order.iter().next().expect("LRU order out of sync");
+            let oldest_key = self.order.remove(&oldest).expect("LRU order out of sync");
+            if oldest_key == key {
+                self.order.insert(oldest, oldest_key);
+                break;
+            }
+            let (oldest_size, _) = self.entries.remove(&oldest_key).expect("LRU entries out of sync");
+            self.used -= oldest_size;
+            evicted.push(oldest_key);
+        }
+        evicted
+    }
+    pub fn remove(&mut self, key: &str) {
+        if let Some((size, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.used -= size;
+        }
+    }
+    /// Removes every key starting with the prefix, returning the removed keys.
+    pub fn remove_prefix(&mut self, prefix: &str) -> Vec<String> {
+        let keys: Vec<String> = self
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        keys.iter().for_each(|key| self.remove(key));
+        keys
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<(String, u64, std::time::SystemTime)>) {
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                collect_files(&path, files);
+            } else if let (Ok(metadata), Ok(relative)) =
+                (entry.metadata(), path.strip_prefix(TRANSFORM_CACHE_ROOT))
+            {
+                files.push((
+                    relative.to_string_lossy().into_owned(),
+                    metadata.len(),
+                    metadata.modified().unwrap_or(std::time::UNIX_EPOCH),
+                ));
+            }
+        }
+    }
+}
+
+fn load_index() -> LruIndex {
+    let capacity = std::env::var("UNION_TRANSFORM_CACHE_BYTES")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_CAPACITY);
+    let mut index = LruIndex::new(capacity);
+    let mut files = vec![];
+    collect_files(Path::new(TRANSFORM_CACHE_ROOT), &mut files);
+    files.sort_by_key(|(_, _, modified)| *modified);
+    for (key, size, _) in files {
+        remove_files(index.insert(key, size));
+    }
+    index
+}
+
+fn remove_files(keys: Vec<String>) {
+    for key in keys {
+        let _ = std::fs::remove_file(format!("{}/{}", TRANSFORM_CACHE_ROOT, key));
+    }
+}
+
+/// Returns the cached bytes for a key, or produces, caches and returns them.
+pub fn get_or_insert<F>(key: &str, produce: F) -> Option<Vec<u8>>
+where
+    F: FnOnce() -> Option<Vec<u8>>,
+{
+    let path = format!("{}/{}", TRANSFORM_CACHE_ROOT, key);
+    if TRANSFORM_CACHE.lock().expect("Transform cache poisoned").touch(key) {
+        if let Ok(cached) = std::fs::read(&path) {
+            return Some(cached);
+        }
+        TRANSFORM_CACHE.lock().expect("Transform cache poisoned").remove(key);
+    }
+    let produced = produce()?;
+    if let Some(parent) = Path::new(&path).parent() {
+        if std::fs::create_dir_all(parent).is_ok() && std::fs::write(&path, &produced).is_ok() {
+            let evicted = TRANSFORM_CACHE
+                .lock()
+                .expect("Transform cache poisoned")
+                .insert(String::from(key), produced.len() as u64);
+            remove_files(evicted);
+        }
+    }
+    Some(produced)
+}
+
+/// Drops every cached transformation under a directory prefix such as `user/gallery/` or
+/// `user/gallery/image.jpg/`.
+pub fn remove_dir(prefix: &str) {
+    let removed = TRANSFORM_CACHE
+        .lock()
+        .expect("Transform cache poisoned")
+        .remove_prefix(prefix);
+    remove_files(removed);
+    let _ = std::fs::remove_dir_all(format!("{}/{}", TRANSFORM_CACHE_ROOT, prefix));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut index = LruIndex::new(10);
+        assert!(index.insert(String::from("a"), 4).is_empty());
+        assert!(index.insert(String::from("b"), 4).is_empty());
+        assert!(index.touch("a"));
+        assert_eq!(index.insert(String::from("c"), 4), vec![String::from("b")]);
+        assert!(!index.touch("b"));
+        assert!(index.touch("a"));
+        assert!(index.touch("c"));
+    }
+    #[test]
+    fn keeps_oversized_entry() {
+        let mut index = LruIndex::new(10);
+        index.insert(String::from("a"), 4);
+        assert_eq!(index.insert(String::from("b"), 20), vec![String::from("a")]);
+        assert!(index.touch("b"));
+    }
+    #[test]
+    fn removes_prefix() {
+        let mut index = LruIndex::new(100);
+        index.insert(String::from("user/gallery/a.jpg/64x64"), 1);
+        index.insert(String::from("user/gallery/a.jpg/128x128"), 1);
+        index.insert(String::from("user/gallery/b.jpg/64x64"), 1);
+        assert_eq!(index.remove_prefix("user/gallery/a.jpg/").len(), 2);
+        assert!(!index.touch("user/gallery/a.jpg/64x64"));
+        assert!(index.touch("user/gallery/b.jpg/64x64"));
+    }
+}
@@ -0,0 +1,280 @@
+use crate::union_structs::TrashRequest;
+use crate::{authenticate_with_id, gallery_operations, mysql_init, static_interface};
+use lazy_static::lazy_static;
+use mysql::params;
+use mysql::prelude::*;
+use serde_json::{json, Value};
+
+lazy_static! {
+    static ref RETENTION_DAYS: u32 = std::env::var("UNION_TRASH_RETENTION_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(30);
+}
+
+/// How often the background job looks for expired trash.
+pub const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Galleries trashed along with their parent are only listed, restored and purged through it.
+const TRASHED_ON_ITS_OWN: &str = "NOT (galleries.deleted <=> parents.deleted)";
+
+/// A trashed gallery that was deleted on its own rather than along with its parent.
+fn trashed_gallery(userid: i32, galleryid: i32) -> Option<mysql::Row> {
+    mysql_init::get_conn()
+        .exec_first(
+            format!(
+                "SELECT galleries.*, parents.deleted IS NOT NULL AS parentdeleted FROM galleries LEFT JOIN galleries AS parents ON galleries.parent=parents.id WHERE galleries.id=:galleryid AND galleries.user=:userid AND galleries.deleted IS NOT NULL AND {}",
+                TRASHED_ON_ITS_OWN
+            ),
+            params!("galleryid"=>galleryid, "userid"=>userid),
+        )
+        .expect("Failed to find trashed gallery")
+}
+
+/// A trashed image in a gallery that is not itself trashed.
+fn trashed_image(userid: i32, imageid: i32) -> Option<mysql::Row> {
+    mysql_init::get_conn()
+        .exec_first(
+            "SELECT images.id, images.name, images.gallery FROM images JOIN galleries ON images.gallery=galleries.id WHERE images.id=:imageid AND galleries.user=:userid AND images.deleted IS NOT NULL AND galleries.deleted IS NULL",
+            params!("imageid"=>imageid, "userid"=>userid),
+        )
+        .expect("Failed to find trashed image")
+}
+
+pub fn list_trash(user_row: &mysql::Row) -> Value {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let mut conn = mysql_init::get_conn();
+    let galleries: Vec<Value> = conn
+        .exec_map(
+            format!(
+                "SELECT galleries.id, galleries.deleted FROM galleries LEFT JOIN galleries AS parents ON galleries.parent=parents.id WHERE galleries.user=:userid AND galleries.deleted IS NOT NULL AND {} ORDER BY galleries.deleted DESC",
+                TRASHED_ON_ITS_OWN
+            ),
+            params!("userid"=>userid),
+            |row: mysql::Row| {
+                let galleryid: i32 = mysql::from_value(row["id"].clone());
+                json!({
+                    "gallery_id": galleryid,
+                    "gallery_name": gallery_operations::gallery_path(galleryid),
+                    "deleted": mysql_init::datetime_string(&row["deleted"]),
+                })
+            },
+        )
+        .expect("Failed to list trashed galleries");
+    let images: Vec<Value> = conn
+        .exec_map(
+            "SELECT images.id, images.name, images.deleted, images.gallery FROM images JOIN galleries ON images.gallery=galleries.id WHERE galleries.user=:userid AND images.deleted IS NOT NULL AND galleries.deleted IS NULL ORDER BY images.deleted DESC",
+            params!("userid"=>userid),
+            |row: mysql::Row| {
+                json!({
+                    "image_id": mysql::from_value::<i32>(row["id"].clone()),
+                    "image_name": mysql::from_value::<String>(row["name"].clone()),
+                    "gallery_name": gallery_operations::gallery_path(mysql::from_value(row["gallery"].clone())),
+                    "deleted": mysql_init::datetime_string(&row["deleted"]),
+                })
+            },
+        )
+        .expect("Failed to list trashed images");
+    json!({
+        "success": true,
+        "retention_days": *RETENTION_DAYS,
+        "galleries": galleries,
+        "images": images,
+    })
+}
+
+/// Restores a trashed gallery along with the galleries that were trashed with it. Fails if its
+/// parent is trashed or a live gallery has taken its name in the meantime.
+pub fn restore_gallery(user_row: &mysql::Row, galleryid: i32) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let username: String = mysql::from_value(user_row["username"].clone());
+    if let Some(gallery) = trashed_gallery(userid, galleryid) {
+        let gallery_name = gallery_operations::gallery_path(galleryid);
+        if mysql::from_value::<bool>(gallery["parentdeleted"].clone())
+            || gallery_operations::find_gallery_id(userid, &gallery_name).is_some()
+        {
+            return false;
+        }
+        mysql_init::get_conn()
+            .exec_drop(
+                format!(
+                    "UPDATE galleries SET deleted=NULL WHERE id IN ({}) AND deleted=:deleted",
+                    gallery_operations::id_list(&gallery_operations::subtree_ids(galleryid)),
+                ),
+                params!("deleted"=>gallery["deleted"].clone()),
+            )
+            .expect("Failed to restore gallery");
+        static_interface::restore_gallery_dir(&username, &gallery_name, galleryid);
+        return true;
+    }
+    false
+}
+
+/// Restores a trashed image, failing if its gallery is trashed or its name has been reused.
+pub fn restore_image(user_row: &mysql::Row, imageid: i32) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    let username: String = mysql::from_value(user_row["username"].clone());
+    if let Some(image) = trashed_image(userid, imageid) {
+        let image_name: String = mysql::from_value(image["name"].clone());
+        let galleryid: i32 = mysql::from_value(image["gallery"].clone());
+        let gallery_name = gallery_operations::gallery_path(galleryid);
+        let name_taken: Option<i32> = mysql_init::get_conn()
+            .exec_first(
+                "SELECT id FROM images WHERE gallery=:galleryid AND name=:imagename AND deleted IS NULL",
+                params!("galleryid"=>galleryid, "imagename"=>&image_name),
+            )
+            .expect("Failed to check for duplicate image");
+        if name_taken.is_some() {
+            return false;
+        }
+        mysql_init::get_conn()
+            .exec_drop(
+                "UPDATE images SET deleted=NULL WHERE id=:imageid",
+                params!("imageid"=>imageid),
+            )
+            .expect("Failed to restore image");
+        gallery_operations::touch_gallery(galleryid);
+        static_interface::restore_image(&username, &gallery_name, &image_name, imageid);
+        return true;
+    }
+    false
+}
+
+fn purge_image_row(imageid: i32) {
+    let mut conn = mysql_init::get_conn();
+    conn.exec_drop("DELETE FROM labelmap WHERE imageid=:imageid", params!("imageid"=>imageid))
+        .expect("Failed to delete image labels");
+    conn.exec_drop("DELETE FROM sharelinks WHERE image=:imageid", params!("imageid"=>imageid))
+        .expect("Failed to delete image share links");
+    conn.exec_drop("DELETE FROM images WHERE id=:imageid", params!("imageid"=>imageid))
+        .expect("Failed to delete image");
+    static_interface::remove_trashed_image(imageid);
+}
+
+/// Permanently deletes a trashed gallery and the galleries nested in it, including images that
+/// were trashed individually.
+fn purge_gallery_row(galleryid: i32) {
+    gallery_operations::subtree_ids(galleryid)
+        .into_iter()
+        .rev()
+        .for_each(purge_single_gallery_row);
+}
+
+fn purge_single_gallery_row(galleryid: i32) {
+    let imageids: Vec<(i32, bool)> = mysql_init::get_conn()
+        .exec_map(
+            "SELECT id, deleted IS NOT NULL FROM images WHERE gallery=:galleryid",
+            params!("galleryid"=>galleryid),
+            |(id, deleted)| (id, deleted),
+        )
+        .expect("Failed to list gallery images");
+    let mut conn = mysql_init::get_conn();
+    for (imageid, deleted) in imageids {
+        conn.exec_drop("DELETE FROM labelmap WHERE imageid=:imageid", params!("imageid"=>imageid))
+            .expect("Failed to delete image labels");
+        if deleted {
+            static_interface::remove_trashed_image(imageid);
+        }
+    }
+    conn.exec_drop("DELETE FROM sharelinks WHERE gallery=:galleryid", params!("galleryid"=>galleryid))
+        .expect("Failed to delete gallery share links");
+    conn.exec_drop("DELETE FROM gallerymembers WHERE gallery=:galleryid", params!("galleryid"=>galleryid))
+        .expect("Failed to delete gallery members");
+    conn.exec_drop("DELETE FROM images WHERE gallery=:galleryid", params!("galleryid"=>galleryid))
+        .expect("Failed to delete gallery images");
+    conn.exec_drop("DELETE FROM galleries WHERE id=:galleryid", params!("galleryid"=>galleryid))
+        .expect("Failed to delete gallery");
+    static_interface::remove_trashed_gallery(galleryid);
+}
+
+pub fn purge_gallery(user_row: &mysql::Row, galleryid: i32) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if trashed_gallery(userid, galleryid).is_some() {
+        purge_gallery_row(galleryid);
+        return true;
+    }
+    false
+}
+
+pub fn purge_image(user_row: &mysql::Row, imageid: i32) -> bool {
+    let userid: i32 = mysql::from_value(user_row["id"].clone());
+    if trashed_image(userid, imageid).is_some() {
+        purge_image_row(imageid);
+        return true;
+    }
+    false
+}
+
+/// Permanently deletes everything that has been in the trash longer than the retention period.
+pub fn purge_expired() {
+    let mut conn = mysql_init::get_conn();
+    let galleryids: Vec<i32> = conn
+        .exec(
+            "SELECT id FROM galleries WHERE deleted < NOW() - INTERVAL :days DAY",
+            params!("days"=>*RETENTION_DAYS),
+        )
+        .expect("Failed to find expired galleries");
+    let imageids: Vec<i32> = conn
+        .exec(
+            "SELECT images.id FROM images JOIN galleries ON images.gallery=galleries.id WHERE images.deleted < NOW() - INTERVAL :days DAY AND galleries.deleted IS NULL",
+            params!("days"=>*RETENTION_DAYS),
+        )
+        .expect("Failed to find expired images");
+    if !galleryids.is_empty() || !imageids.is_empty() {
+        println!(
+            "Purging {} galleries and {} images from the trash",
+            galleryids.len(),
+            imageids.len()
+        );
+    }
+    galleryids.into_iter().for_each(purge_gallery_row);
+    imageids.into_iter().for_each(purge_image_row);
+}
+
+fn trash_results(
+    user_row: &mysql::Row,
+    request: &TrashRequest,
+    gallery_action: fn(&mysql::Row, i32) -> bool,
+    image_action: fn(&mysql::Row, i32) -> bool,
+) -> Value {
+    let galleries: Vec<Value> = request
+        .get_gallery_ids()
+        .into_iter()
+        .map(|galleryid| json!({"gallery_id": galleryid, "success": gallery_action(user_row, galleryid)}))
+        .collect();
+    let images: Vec<Value> = request
+        .get_image_ids()
+        .into_iter()
+        .map(|imageid| json!({"image_id": imageid, "success": image_action(user_row, imageid)}))
+        .collect();
+    json!({"success": true, "galleries": galleries, "images": images})
+}
+
+pub fn handle_trash_list(json: Value) -> Value {
+    match serde_json::from_value::<TrashRequest>(json)
+        .ok()
+        .and_then(|request| request.get_id())
+        .and_then(authenticate_with_id)
+    {
+        Some(user_row) => list_trash(&user_row),
+        None => json!({"success": false}),
+    }
+}
+
+pub fn handle_trash_restore(json: Value) -> Value {
+    if let Ok(request) = serde_json::from_value::<TrashRequest>(json) {
+        if let Some(user_row) = request.get_id().and_then(authenticate_with_id) {
+            return trash_results(&user_row, &request, restore_gallery, restore_image);
+        }
+    }
+    json!({"success": false})
+}
+
+pub fn handle_trash_purge(json: Value) -> Value {
+    if let Ok(request) = serde_json::from_value::<TrashRequest>(json) {
+        if let Some(user_row) = request.get_id().and_then(authenticate_with_id) {
+            return trash_results(&user_row, &request, purge_gallery, purge_image);
+        }
+    }
+    json!({"success": false})
+}
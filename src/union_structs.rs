@@ -2,6 +2,7 @@ use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use utoipa::{IntoParams, ToSchema};
 
 const USERNAME_ERROR_MESSAGE: &str = "Usernames must be between 4 and 16 characters long with only letters, numbers, and underscores (_).";
 const GALLERY_NAME_ERROR_MESSAGE: &str = "Gallery names must be between 1 and 128 characters long with only letters, numbers, and underscores (_).";
@@ -10,14 +11,23 @@ const PASSWORD_ERROR_MESSAGE: &str = "Passwords must be between 8 and 64 charact
 const LABEL_ERROR_MESSAGE: &str = "Labels must be between 4 and 64 characters long with only letters, numbers, underscores (_), and at signs (@). ";
 
 lazy_static! {
-    static ref USERNAME_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_]{4,16}$").unwrap();
+    pub static ref USERNAME_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_]{4,16}$").unwrap();
     pub static ref GALLERY_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_]{1,128}$").unwrap();
+    static ref GALLERY_PATH_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_]{1,128}(/[a-zA-Z0-9_]{1,128})*$").unwrap();
     pub static ref IMAGETITLE_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_\-.#]{1,128}.[Jj][Pp][Ee]?[gG]$").unwrap();
     static ref PASSWORD_REGEX: Regex = Regex::new(r"^.{8,64}$").unwrap();
-    static ref LABEL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_@]{4,64}$").unwrap();
+    pub static ref LABEL_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9_@]{4,64}$").unwrap();
     static ref EMAIL_REGEX: Regex =
         Regex::new(r"^(([a-z0-9_+.]{1,32})@([a-z0-9\-\.]{1,32})\.([a-z]{2,6}))$").unwrap();
     pub static ref ID_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9]{255}$").unwrap();
+    pub static ref SHARE_TOKEN_REGEX: Regex = Regex::new(r"^[a-zA-Z0-9]{48}$").unwrap();
+    pub static ref API_TOKEN_REGEX: Regex = Regex::new(r"^pat_[a-zA-Z0-9]{60}$").unwrap();
+    static ref TOKEN_NAME_REGEX: Regex = Regex::new(r"^[^\r\n]{1,64}$").unwrap();
+    static ref DESCRIPTION_REGEX: Regex = Regex::new(r"(?s)^.{0,1000}$").unwrap();
+    static ref IMAGE_TITLE_TEXT_REGEX: Regex = Regex::new(r"^[^\r\n]{0,256}$").unwrap();
+    static ref ALT_TEXT_REGEX: Regex = Regex::new(r"^[^\r\n]{0,1000}$").unwrap();
+    static ref DATETIME_REGEX: Regex =
+        Regex::new(r"^\d{4}-(0[1-9]|1[0-2])-(0[1-9]|[12]\d|3[01])( ([01]\d|2[0-3]):[0-5]\d(:[0-5]\d)?)?$").unwrap();
 }
 
 pub fn parse(regex: &Regex, unverified: &str) -> Option<String> {
@@ -27,6 +37,23 @@ pub fn parse(regex: &Regex, unverified: &str) -> Option<String> {
         None
     }
 }
+/// Validates the `id` of a websocket operation, which is a session id or an API token.
+pub fn parse_credential(unverified: &str) -> Option<String> {
+    parse(&ID_REGEX, unverified).or_else(|| parse(&API_TOKEN_REGEX, unverified))
+}
+
+/// Validates a gallery path such as `2022/summer/beach`, where each segment is a gallery name
+/// nested in the one before it.
+pub fn parse_gallery_path(unverified: &str) -> Option<String> {
+    if unverified.len() <= MAX_GALLERY_PATH_LENGTH {
+        parse(&GALLERY_PATH_REGEX, unverified)
+    } else {
+        None
+    }
+}
+
+const MAX_GALLERY_PATH_LENGTH: usize = 1024;
+
 pub struct InputError {
     message: String,
 }
@@ -204,6 +231,20 @@ mod tests {
         });
     }
     #[test]
+    fn gallery_paths() {
+        vec!["2022", "2022/summer", "2022/summer/beach_day_1"]
+            .into_iter()
+            .for_each(|path| {
+                assert!(parse_gallery_path(path).is_some());
+            });
+        let too_long = vec!["a"; 600].join("/");
+        vec!["", "/2022", "2022/", "2022//summer", "2022/sum mer", "../etc", too_long.as_str()]
+            .into_iter()
+            .for_each(|path| {
+                assert!(parse_gallery_path(path).is_none());
+            });
+    }
+    #[test]
     fn good_image_names() {
         vec!["Somebody62.jpg", "#DCIM-546_rev.2.jpg", "#.jpg", "fd.JPeG"]
             .into_iter()
@@ -277,12 +318,140 @@ mod tests {
         });
     }
     #[test]
+    fn image_statuses() {
+        vec![
+            (ImageStatus::InvalidName, "invalid_name"),
+            (ImageStatus::UnknownGallery, "unknown_gallery"),
+            (ImageStatus::OverQuota, "over_quota"),
+            (ImageStatus::MissingAltText, "missing_alt_text"),
+        ]
+        .into_iter()
+        .for_each(|(status, name)| {
+            assert_eq!(serde_json::to_value(status).unwrap(), name);
+        });
+    }
+    #[test]
+    fn image_text_fields() {
+        let describe: ImageDescribe = serde_json::from_value(serde_json::json!({
+            "id": "x",
+            "image_id": 1,
+            "title": "Sunset",
+            "caption": "Over the bay,\nfrom the pier",
+        }))
+        .unwrap();
+        assert_eq!(describe.get_title(), Ok(Some(String::from("Sunset"))));
+        assert!(describe.get_caption().is_ok());
+        assert_eq!(describe.get_alt_text(), Ok(None));
+        let describe: ImageDescribe = serde_json::from_value(serde_json::json!({
+            "id": "x",
+            "image_id": 1,
+            "title": "Two\nlines",
+            "alt_text": "a".repeat(1001),
+        }))
+        .unwrap();
+        assert!(describe.get_title().is_err());
+        assert!(describe.get_alt_text().is_err());
+    }
+    #[test]
+    fn single_and_bulk_image_ids() {
+        let delete: ImageDelete =
+            serde_json::from_value(serde_json::json!({"id": "x", "image_id": 3, "image_ids": [1, 2]}))
+                .unwrap();
+        assert_eq!(delete.get_image_ids(), vec![1, 2, 3]);
+        let rename: ImageRename = serde_json::from_value(serde_json::json!({
+            "id": "x",
+            "renames": [{"image_id": 1, "image_name": "a.jpg"}, {"image_id": 2, "image_name": "b$.jpg"}]
+        }))
+        .unwrap();
+        assert_eq!(
+            rename.get_renames(),
+            vec![(1, Some(String::from("a.jpg"))), (2, None)]
+        );
+    }
+    #[test]
+    fn gallery_delete_confirmation() {
+        let confirmed: GalleryDelete = serde_json::from_value(
+            serde_json::json!({"id": "x", "gallery_name": "Trip", "confirm_name": "Trip"}),
+        )
+        .unwrap();
+        assert_eq!(confirmed.get_gallery_name(), Some(String::from("Trip")));
+        let unconfirmed: GalleryDelete = serde_json::from_value(
+            serde_json::json!({"id": "x", "gallery_name": "Trip", "confirm_name": "trip"}),
+        )
+        .unwrap();
+        assert!(unconfirmed.get_gallery_name().is_none());
+    }
+    #[test]
+    fn image_listings() {
+        let listing: ImageListing = serde_json::from_value(
+            serde_json::json!({"order": "desc", "label": "holiday", "from": "2022-06-01", "to": "2022-06-30"}),
+        )
+        .unwrap();
+        assert_eq!(listing.get_descending(), Ok(true));
+        assert_eq!(listing.get_label(), Ok(Some(String::from("holiday"))));
+        assert_eq!(listing.get_from(), Ok(Some(String::from("2022-06-01"))));
+        assert_eq!(listing.get_to(), Ok(Some(String::from("2022-06-30 23:59:59"))));
+        let bad: ImageListing =
+            serde_json::from_value(serde_json::json!({"order": "up", "to": "June"})).unwrap();
+        assert!(bad.get_descending().is_err());
+        assert!(bad.get_to().is_err());
+        assert_eq!(bad.get_label(), Ok(None));
+    }
+    #[test]
+    fn token_creation() {
+        let token: TokenCreate = serde_json::from_value(serde_json::json!({
+            "id": "x", "name": "Backups", "scopes": ["upload", "read", "upload"], "expires": "2030-01-01"
+        }))
+        .unwrap();
+        assert_eq!(token.get_name(), Some(String::from("Backups")));
+        assert_eq!(token.get_scopes(), Some(vec![Scope::Read, Scope::Upload]));
+        assert_eq!(token.get_expires(), Ok(Some(String::from("2030-01-01"))));
+        let empty: TokenCreate = serde_json::from_value(
+            serde_json::json!({"id": "x", "name": "", "scopes": [], "expires": "soon"}),
+        )
+        .unwrap();
+        assert!(empty.get_name().is_none());
+        assert!(empty.get_scopes().is_none());
+        assert!(empty.get_expires().is_err());
+        assert!(serde_json::from_value::<TokenCreate>(
+            serde_json::json!({"id": "x", "name": "a", "scopes": ["write"]})
+        )
+        .is_err());
+        assert!(Scope::Admin > Scope::Upload && Scope::Upload > Scope::Read);
+        for scope in [Scope::Read, Scope::Upload, Scope::Admin].iter() {
+            assert_eq!(Scope::from_name(scope.name()), Some(*scope));
+        }
+    }
+    #[test]
+    fn good_datetimes() {
+        vec!["2022-12-31", "2023-01-01 00:00", "2023-06-15 23:59:59"]
+            .into_iter()
+            .for_each(|datetime| {
+                assert!(parse(&DATETIME_REGEX, datetime).is_some());
+            });
+    }
+    #[test]
+    fn bad_datetimes() {
+        vec!["2022-13-01", "2022-12-32", "2023-01-01 24:00", "tomorrow", "2023-01-01T00:00"]
+            .into_iter()
+            .for_each(|datetime| {
+                assert!(parse(&DATETIME_REGEX, datetime).is_none());
+            });
+    }
+    #[test]
     fn good_ids() {
         vec!["Bgb3IqYnBrC9MVfvvTW3h2jLd8O7Q0Cz7acpkR17DfPliZJjwpD6yEfgT19M2b6C3pPoOWJSwCmGXlTHmE864D2yWGsAZtegKWK61BwjINRL2br8W1pQC9tYNhZxongAB1TDlzcbIk9NQNJbXneHEx1tQEiiEb651zSQAjvA77QHIVCkOaa6WE2dkwrkVHDaKCCqQ1v1GY73nro6rIUelzQWCrsfdATB2dfuHLbwOXpMq9PEQCpWNaiVVstDuh0"].into_iter().for_each(|id| {
             assert!(parse(&ID_REGEX, id).is_some());
         });
     }
     #[test]
+    fn credentials() {
+        let token = format!("pat_{}", "a".repeat(60));
+        assert_eq!(parse_credential(&token), Some(token));
+        assert_eq!(parse_credential(&"a".repeat(255)), Some("a".repeat(255)));
+        assert!(parse_credential("pat_short").is_none());
+    }
+    #[test]
     fn bad_ids() {
         vec!["Bgb3IqYnBrC9MVfvvTW3h2jLd8O7Q0Cz7acpkR17DfPliZJjwpD6yEfgT19M2b6C3pPoOWJSwCmGXlTHmE864D2yWGsAZtegKWK61BwjINRL2br8W1pQC9tYNhZxongAB1TDlzcbIk9NQNJbXneHEx1tQEiiEb651zSQAjvA77QHIVCkOaa6WE2dkwrkVHDaKCCqQ1v1GY73nro6rIUelzQWCrsfdATB2dfuHLbwOXpMq9PEQCpWNaiVVstDuh_", "Bgb3IqYnBrC9MVfvvTW3h2jLd8O7Q0Cz7acpkR17DfPliZJjwpD6yEfgT19M2b6C3pPoOWJSwCmGXlTHmE864D2yWGsAZtegKWK61BwjINRL2br8W1pQC9tYNhZxongAB1TDlzcbIk9NQNJbXneHEx1tQEiiEb651zSQAjvA77QHIVCkOaa6WE2dkwrkVHDaKCCqQ1v1GY73nro6rIUelzQWCrsfdATB2dfuHLbwOXpMq9PEQCpWNaiVVstDuh"].into_iter().for_each(|id| {
             assert!(parse(&ID_REGEX, id).is_none());
@@ -336,7 +505,7 @@ impl GalleryDetails {
 }
 */
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct Signup {
     email: String,
     password: String,
@@ -355,7 +524,7 @@ impl Signup {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct Login {
     email: String,
     password: String,
@@ -370,26 +539,145 @@ impl Login {
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 pub struct GalleryCreate {
     gallery_name: String,
+    #[schema(ignore)]
     id: String,
+    visibility: Option<String>,
+    description: Option<String>,
 }
 
 impl GalleryCreate {
     pub fn get_gallery_name(&self) -> Option<String> {
-        parse(&GALLERY_REGEX, &self.gallery_name)
+        parse_gallery_path(&self.gallery_name)
     }
     pub fn get_id(&self) -> Option<String> {
-        parse(&ID_REGEX, &self.id)
+        parse_credential(&self.id)
+    }
+    pub fn get_visibility(&self) -> Option<String> {
+        self.visibility.clone()
+    }
+    pub fn get_description(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.description)
+    }
+}
+
+fn optional_parse(regex: &Regex, unverified: &Option<String>) -> Result<Option<String>, ()> {
+    match unverified {
+        Some(unverified) => parse(regex, unverified).map(Some).ok_or(()),
+        None => Ok(None),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GalleryUpdate {
+    id: String,
+    gallery_name: String,
+    description: Option<String>,
+    cover_image: Option<String>,
+}
+
+impl GalleryUpdate {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    /// The new description, `Ok(None)` to leave it unchanged. An empty description clears it.
+    pub fn get_description(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.description)
+    }
+    /// The name of the new cover image, `Ok(None)` to leave it unchanged. An empty name clears it.
+    pub fn get_cover_image(&self) -> Result<Option<String>, ()> {
+        match self.cover_image.as_deref() {
+            Some("") => Ok(Some(String::new())),
+            Some(cover_image) => parse(&IMAGETITLE_REGEX, cover_image).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GalleryReorder {
+    id: String,
+    gallery_names: Vec<String>,
+}
+
+impl GalleryReorder {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    /// The galleries in their new order, or None if any name is invalid.
+    pub fn get_gallery_names(&self) -> Option<Vec<String>> {
+        self.gallery_names
+            .iter()
+            .map(|gallery_name| parse_gallery_path(gallery_name))
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GalleryVisibility {
+    id: String,
+    gallery_name: String,
+    visibility: String,
+}
+
+impl GalleryVisibility {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_visibility(&self) -> String {
+        self.visibility.clone()
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct SettingsUpdate {
+    #[schema(ignore)]
+    id: String,
+    strip_metadata: Option<String>,
+    keep_original_metadata: Option<bool>,
+    gallery_order: Option<String>,
+    accessibility_strict: Option<bool>,
+}
+
+impl SettingsUpdate {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_strip_metadata(&self) -> Option<String> {
+        self.strip_metadata.clone()
+    }
+    pub fn get_keep_original_metadata(&self) -> Option<bool> {
+        self.keep_original_metadata
+    }
+    pub fn get_gallery_order(&self) -> Option<String> {
+        self.gallery_order.clone()
+    }
+    pub fn get_accessibility_strict(&self) -> Option<bool> {
+        self.accessibility_strict
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, ToSchema)]
 pub struct ImageCreate {
     image_name: String,
+    /// The image file, base64 encoded.
     image: String,
-    gallery_name: String
+    #[schema(ignore)]
+    gallery_name: String,
+    auto_orient: Option<bool>,
+    #[schema(ignore)]
+    owner: Option<String>,
+    title: Option<String>,
+    caption: Option<String>,
+    alt_text: Option<String>,
 }
 
 impl ImageCreate {
@@ -400,6 +688,623 @@ impl ImageCreate {
         Some(self.image.clone())
     }
     pub fn get_gallery_name(&self) -> Option<String> {
-        parse(&GALLERY_REGEX, &self.gallery_name)
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_auto_orient(&self) -> bool {
+        self.auto_orient.unwrap_or(false)
+    }
+    /// The owner of the target gallery, `Ok(None)` when uploading to one's own gallery.
+    pub fn get_owner(&self) -> Result<Option<String>, ()> {
+        match &self.owner {
+            Some(owner) => parse(&USERNAME_REGEX, owner).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_title(&self) -> Result<Option<String>, ()> {
+        optional_parse(&IMAGE_TITLE_TEXT_REGEX, &self.title)
+    }
+    pub fn get_caption(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.caption)
+    }
+    pub fn get_alt_text(&self) -> Result<Option<String>, ()> {
+        optional_parse(&ALT_TEXT_REGEX, &self.alt_text)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImageDescribe {
+    id: String,
+    image_id: i32,
+    title: Option<String>,
+    caption: Option<String>,
+    alt_text: Option<String>,
+}
+
+impl ImageDescribe {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_image_id(&self) -> i32 {
+        self.image_id
+    }
+    /// The new title, `Ok(None)` to leave it unchanged. An empty title clears it.
+    pub fn get_title(&self) -> Result<Option<String>, ()> {
+        optional_parse(&IMAGE_TITLE_TEXT_REGEX, &self.title)
+    }
+    pub fn get_caption(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.caption)
+    }
+    pub fn get_alt_text(&self) -> Result<Option<String>, ()> {
+        optional_parse(&ALT_TEXT_REGEX, &self.alt_text)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImageRotate {
+    id: String,
+    gallery_name: String,
+    image_name: String,
+    degrees: u32,
+}
+
+impl ImageRotate {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_image_name(&self) -> Option<String> {
+        parse(&IMAGETITLE_REGEX, &self.image_name)
+    }
+    pub fn get_degrees(&self) -> Option<u32> {
+        match self.degrees {
+            90 | 180 | 270 => Some(self.degrees),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageStatus {
+    Malformed,
+    InvalidName,
+    UnknownGallery,
+    BadEncoding,
+    Duplicate,
+    OverQuota,
+    MissingAltText,
+    /// The image was stored but its renditions could not be generated.
+    RenditionsFailed,
+}
+
+#[derive(Deserialize)]
+pub struct ImageDelete {
+    id: String,
+    image_id: Option<i32>,
+    image_ids: Option<Vec<i32>>,
+}
+
+impl ImageDelete {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_image_ids(&self) -> Vec<i32> {
+        image_ids(self.image_id, &self.image_ids)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RenameEntry {
+    image_id: i32,
+    image_name: String,
+}
+
+impl RenameEntry {
+    pub fn get_image_id(&self) -> i32 {
+        self.image_id
+    }
+    pub fn get_image_name(&self) -> Option<String> {
+        parse(&IMAGETITLE_REGEX, &self.image_name)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImageRename {
+    id: String,
+    image_id: Option<i32>,
+    image_name: Option<String>,
+    renames: Option<Vec<RenameEntry>>,
+}
+
+impl ImageRename {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    /// The requested renames as (image id, validated new name) pairs.
+    pub fn get_renames(&self) -> Vec<(i32, Option<String>)> {
+        let mut renames: Vec<(i32, Option<String>)> = self
+            .renames
+            .iter()
+            .flatten()
+            .map(|rename| (rename.get_image_id(), rename.get_image_name()))
+            .collect();
+        if let (Some(image_id), Some(image_name)) = (self.image_id, &self.image_name) {
+            renames.push((image_id, parse(&IMAGETITLE_REGEX, image_name)));
+        }
+        renames
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ImageMove {
+    id: String,
+    image_id: Option<i32>,
+    image_ids: Option<Vec<i32>>,
+    gallery_name: String,
+}
+
+impl ImageMove {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_image_ids(&self) -> Vec<i32> {
+        image_ids(self.image_id, &self.image_ids)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TrashRequest {
+    id: String,
+    image_ids: Option<Vec<i32>>,
+    gallery_ids: Option<Vec<i32>>,
+}
+
+impl TrashRequest {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_image_ids(&self) -> Vec<i32> {
+        self.image_ids.clone().unwrap_or_default()
+    }
+    pub fn get_gallery_ids(&self) -> Vec<i32> {
+        self.gallery_ids.clone().unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionRequest {
+    id: String,
+}
+
+impl SessionRequest {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareCreate {
+    id: String,
+    gallery_name: String,
+    image_name: Option<String>,
+    expires: Option<String>,
+    password: Option<String>,
+    download: Option<bool>,
+}
+
+impl ShareCreate {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    /// The shared image, `Ok(None)` when the whole gallery is shared.
+    pub fn get_image_name(&self) -> Result<Option<String>, ()> {
+        match &self.image_name {
+            Some(image_name) => parse(&IMAGETITLE_REGEX, image_name).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_expires(&self) -> Result<Option<String>, ()> {
+        match &self.expires {
+            Some(expires) => parse(&DATETIME_REGEX, expires).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_password(&self) -> Result<Option<String>, ()> {
+        match &self.password {
+            Some(password) => parse(&PASSWORD_REGEX, password).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_download(&self) -> bool {
+        self.download.unwrap_or(false)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ShareRevoke {
+    id: String,
+    token: String,
+}
+
+impl ShareRevoke {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_token(&self) -> Option<String> {
+        parse(&SHARE_TOKEN_REGEX, &self.token)
+    }
+}
+
+/// What an API token may do. Each scope includes the ones before it: `upload` can also read and
+/// `admin` can do anything the user can.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    Read,
+    Upload,
+    Admin,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Upload => "upload",
+            Scope::Admin => "admin",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Scope> {
+        match name {
+            "read" => Some(Scope::Read),
+            "upload" => Some(Scope::Upload),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct TokenCreate {
+    #[schema(ignore)]
+    id: String,
+    name: String,
+    scopes: Vec<Scope>,
+    /// When the token stops working, `YYYY-MM-DD` or `YYYY-MM-DD HH:MM[:SS]`. Tokens without one
+    /// work until they are revoked.
+    expires: Option<String>,
+}
+
+impl TokenCreate {
+    pub fn get_id(&self) -> Option<String> {
+        parse(&ID_REGEX, &self.id)
+    }
+    pub fn get_name(&self) -> Option<String> {
+        parse(&TOKEN_NAME_REGEX, &self.name)
+    }
+    /// The requested scopes without duplicates, None if there are none.
+    pub fn get_scopes(&self) -> Option<Vec<Scope>> {
+        let mut scopes = self.scopes.clone();
+        scopes.sort();
+        scopes.dedup();
+        Some(scopes).filter(|scopes| !scopes.is_empty())
+    }
+    pub fn get_expires(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DATETIME_REGEX, &self.expires)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct TokenRevoke {
+    id: String,
+    token_id: i32,
+}
+
+impl TokenRevoke {
+    pub fn get_id(&self) -> Option<String> {
+        parse(&ID_REGEX, &self.id)
+    }
+    pub fn get_token_id(&self) -> i32 {
+        self.token_id
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MemberInvite {
+    id: String,
+    gallery_name: String,
+    username: String,
+    role: String,
+}
+
+impl MemberInvite {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_username(&self) -> Option<String> {
+        parse(&USERNAME_REGEX, &self.username)
+    }
+    pub fn get_role(&self) -> String {
+        self.role.clone()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InvitationResponse {
+    id: String,
+    owner: String,
+    gallery_name: String,
+    accept: bool,
+}
+
+impl InvitationResponse {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_owner(&self) -> Option<String> {
+        parse(&USERNAME_REGEX, &self.owner)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_accept(&self) -> bool {
+        self.accept
+    }
+}
+
+#[derive(Deserialize)]
+pub struct MemberRemove {
+    id: String,
+    owner: Option<String>,
+    gallery_name: String,
+    username: String,
+}
+
+impl MemberRemove {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    /// The gallery's owner. Owners removing members of their own galleries may leave it out.
+    pub fn get_owner(&self) -> Option<String> {
+        self.owner.as_deref().and_then(|owner| parse(&USERNAME_REGEX, owner))
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_username(&self) -> Option<String> {
+        parse(&USERNAME_REGEX, &self.username)
+    }
+}
+
+fn image_ids(image_id: Option<i32>, image_ids: &Option<Vec<i32>>) -> Vec<i32> {
+    let mut ids = image_ids.clone().unwrap_or_default();
+    ids.extend(image_id);
+    ids
+}
+
+#[derive(Deserialize)]
+pub struct GalleryRename {
+    id: String,
+    gallery_name: String,
+    new_name: String,
+}
+
+impl GalleryRename {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    pub fn get_new_name(&self) -> Option<String> {
+        parse(&GALLERY_REGEX, &self.new_name)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GalleryMove {
+    id: String,
+    gallery_name: String,
+    parent: Option<String>,
+}
+
+impl GalleryMove {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_name(&self) -> Option<String> {
+        parse_gallery_path(&self.gallery_name)
+    }
+    /// The new parent gallery, `Ok(None)` to move the gallery to the top level.
+    pub fn get_parent(&self) -> Result<Option<String>, ()> {
+        match &self.parent {
+            Some(parent) => parse_gallery_path(parent).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GalleryDelete {
+    id: String,
+    gallery_name: String,
+    confirm_name: String,
+}
+
+impl GalleryDelete {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    /// The gallery to delete, only returned if `confirm_name` repeats it exactly.
+    pub fn get_gallery_name(&self) -> Option<String> {
+        if self.confirm_name == self.gallery_name {
+            parse_gallery_path(&self.gallery_name)
+        } else {
+            None
+        }
+    }
+}
+
+/// One request on a multiplexed websocket. `id` is chosen by the client and echoed in the reply.
+#[derive(Deserialize)]
+pub struct Envelope {
+    id: Option<serde_json::Value>,
+    op: String,
+    payload: Option<serde_json::Value>,
+}
+
+impl Envelope {
+    pub fn get_request_id(&self) -> serde_json::Value {
+        self.id.clone().unwrap_or(serde_json::Value::Null)
+    }
+    pub fn get_op(&self) -> String {
+        self.op.clone()
+    }
+    pub fn get_payload(&self) -> serde_json::Value {
+        self.payload
+            .clone()
+            .unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new()))
+    }
+}
+
+/// A subscription to the changes in one gallery, or in all of the user's galleries if there is no
+/// gallery id.
+#[derive(Deserialize)]
+pub struct Subscription {
+    id: String,
+    gallery_id: Option<i32>,
+}
+
+impl Subscription {
+    pub fn get_id(&self) -> Option<String> {
+        parse_credential(&self.id)
+    }
+    pub fn get_gallery_id(&self) -> Option<i32> {
+        self.gallery_id
+    }
+}
+
+/// Sorting and filtering for a gallery's images, given as query parameters on gallery pages and
+/// listings.
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImageListing {
+    sort: Option<String>,
+    order: Option<String>,
+    format: Option<String>,
+    label: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl ImageListing {
+    pub fn get_sort(&self) -> Option<String> {
+        self.sort.clone()
+    }
+    pub fn get_descending(&self) -> Result<bool, ()> {
+        match self.order.as_deref() {
+            None | Some("asc") => Ok(false),
+            Some("desc") => Ok(true),
+            Some(_) => Err(()),
+        }
+    }
+    pub fn get_format(&self) -> Option<String> {
+        self.format.clone()
+    }
+    pub fn get_label(&self) -> Result<Option<String>, ()> {
+        optional_parse(&LABEL_REGEX, &self.label)
+    }
+    pub fn get_from(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DATETIME_REGEX, &self.from)
+    }
+    /// The end of the date range. A date on its own includes the whole day.
+    pub fn get_to(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DATETIME_REGEX, &self.to).map(|to| {
+            to.map(|to| if to.len() == 10 { format!("{} 23:59:59", to) } else { to })
+        })
+    }
+}
+
+/// Changes to a gallery through the REST API. Absent fields are left unchanged.
+#[derive(Deserialize, ToSchema)]
+pub struct GalleryPatch {
+    name: Option<String>,
+    /// The path of the new parent gallery, empty to move the gallery to the top level.
+    parent: Option<String>,
+    visibility: Option<String>,
+    description: Option<String>,
+    cover_image: Option<String>,
+}
+
+impl GalleryPatch {
+    pub fn get_name(&self) -> Result<Option<String>, ()> {
+        optional_parse(&GALLERY_REGEX, &self.name)
+    }
+    /// The new parent gallery, `Ok(Some(None))` to move the gallery to the top level, which an
+    /// empty parent asks for.
+    pub fn get_parent(&self) -> Result<Option<Option<String>>, ()> {
+        match self.parent.as_deref() {
+            Some("") => Ok(Some(None)),
+            Some(parent) => parse_gallery_path(parent).map(|parent| Some(Some(parent))).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_visibility(&self) -> Option<String> {
+        self.visibility.clone()
+    }
+    pub fn get_description(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.description)
+    }
+    pub fn get_cover_image(&self) -> Result<Option<String>, ()> {
+        match self.cover_image.as_deref() {
+            Some("") => Ok(Some(String::new())),
+            Some(cover_image) => parse(&IMAGETITLE_REGEX, cover_image).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Changes to an image through the REST API. Absent fields are left unchanged.
+#[derive(Deserialize, ToSchema)]
+pub struct ImagePatch {
+    name: Option<String>,
+    gallery_name: Option<String>,
+    title: Option<String>,
+    caption: Option<String>,
+    alt_text: Option<String>,
+}
+
+impl ImagePatch {
+    pub fn get_name(&self) -> Result<Option<String>, ()> {
+        optional_parse(&IMAGETITLE_REGEX, &self.name)
+    }
+    /// The gallery to move the image to.
+    pub fn get_gallery_name(&self) -> Result<Option<String>, ()> {
+        match &self.gallery_name {
+            Some(gallery_name) => parse_gallery_path(gallery_name).map(Some).ok_or(()),
+            None => Ok(None),
+        }
+    }
+    pub fn get_title(&self) -> Result<Option<String>, ()> {
+        optional_parse(&IMAGE_TITLE_TEXT_REGEX, &self.title)
+    }
+    pub fn get_caption(&self) -> Result<Option<String>, ()> {
+        optional_parse(&DESCRIPTION_REGEX, &self.caption)
+    }
+    pub fn get_alt_text(&self) -> Result<Option<String>, ()> {
+        optional_parse(&ALT_TEXT_REGEX, &self.alt_text)
+    }
+    pub fn has_description(&self) -> bool {
+        self.title.is_some() || self.caption.is_some() || self.alt_text.is_some()
     }
 }